@@ -1,62 +1,286 @@
 extern crate i2c_linux;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::result;
+use std::time::{Duration, SystemTime};
 
 use i2c_linux::I2c;
+use log::{debug, trace};
+
+use crate::bus::Bus;
+use crate::scale::{Scale, FULL_SCALE};
 
 // use i2cdev::core::*;
 // use i2cdev::linux::{LinuxI2CDevice, LinuxI2CError, LinuxI2CMessage};
 
-const SLAVE_ADDR: u16 = 0x54;
+pub const BUS_PATH: &str = "/dev/i2c-2";
+pub const SLAVE_ADDR: u16 = 0x54;
+
+// 7-bit addresses selectable with the ADC121C02x address pins
+pub const SLAVE_ADDRS: [u16; 9] = [0x50, 0x51, 0x52, 0x54, 0x55, 0x56, 0x58, 0x59, 0x5A];
+
+// errno returned by i2c adapters when the slave doesn't ACK its address
+const ENXIO: i32 = 6;
+const EREMOTEIO: i32 = 121;
+
+#[derive(Debug)]
+pub enum Error {
+    //i2c bus device can't be opened
+    Open(PathBuf, io::Error),
+    //slave address out of the ADC121C02x range
+    Address(u16),
+    //no device ACK (NACK) at the slave address
+    NoDevice(io::Error),
+    //transfer error in the bus
+    Io(io::Error),
+    //value can't be written in a 12-bit register
+    OutOfRange(f32),
+    //register content not allowed by the ADC121C02x
+    Register { addr: u8, value: u16 },
+    //register read back differs from the written value
+    Verify { addr: u8, expected: u16, found: u16 },
+    //operation not completed in time (async_adc)
+    Timeout(Duration),
+    //operations queued behind one still running (async_adc)
+    Busy,
+    //ADC worker stopped (async_adc)
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Open(path, err) => write!(f, "can't open i2c bus {}: {}", path.display(), err),
+            Error::Address(address) => write!(f, "invalid ADC121C02x slave address: {:#X}", address),
+            Error::NoDevice(err) => write!(f, "no ADC device in the bus: {}", err),
+            Error::Io(err) => write!(f, "i2c transfer error: {}", err),
+            Error::OutOfRange(value) => write!(f, "value out of ADC range: {}", value),
+            Error::Register { addr, value } => {
+                write!(f, "unexpected content in register {:#X}: {:#X}", addr, value)
+            }
+            Error::Verify { addr, expected, found } => write!(
+                f,
+                "register {:#X} verify error, expected: {:#X}, found: {:#X}",
+                addr, expected, found
+            ),
+            Error::Timeout(timeout) => write!(f, "ADC operation timeout after {:?}", timeout),
+            Error::Busy => write!(f, "ADC worker busy, previous operation still running"),
+            Error::Closed => write!(f, "ADC worker closed"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Open(_, err) | Error::NoDevice(err) | Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        match err.raw_os_error() {
+            Some(ENXIO) | Some(EREMOTEIO) => Error::NoDevice(err),
+            _ => Error::Io(err),
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+//conversion result
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    //12-bit code
+    pub raw: u16,
+    pub volts: f32,
+    pub alert: bool,
+    pub timestamp: SystemTime,
+}
 
-pub struct ADC {
-    dev: I2c
+pub struct ADC<B: Bus = I2c<File>> {
+    dev: B,
+    scale: Scale,
 }
 
 
 
-pub enum FlagRegister {
-    AlertHold = 0x10,
-    AlertFlagEnable = 0x08,
-    AlertPINEnable = 0x04,
-    Polarity = 0x01,
-    Tx32=0x20,
+//automatic conversion cycle time (Tconvert multiples), bits D7-D5 of the configuration register
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CycleTime {
+    #[default]
+    Disabled = 0b000,
+    Tx32 = 0b001,
+    Tx64 = 0b010,
+    Tx128 = 0b011,
+    Tx256 = 0b100,
+    Tx512 = 0b101,
+    Tx1024 = 0b110,
+    Tx2048 = 0b111,
 }
 
-impl ADC {
+impl CycleTime {
+    fn from_bits(bits: u8) -> CycleTime {
+        match bits & 0b111 {
+            0b001 => CycleTime::Tx32,
+            0b010 => CycleTime::Tx64,
+            0b011 => CycleTime::Tx128,
+            0b100 => CycleTime::Tx256,
+            0b101 => CycleTime::Tx512,
+            0b110 => CycleTime::Tx1024,
+            0b111 => CycleTime::Tx2048,
+            _ => CycleTime::Disabled,
+        }
+    }
+}
 
-    //New ADC
+//configuration register (0x02)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Config {
+    pub cycle_time: CycleTime,
+    //alert status held until cleared
+    pub alert_hold: bool,
+    //alert flag in the conversion result (bit 15)
+    pub alert_flag_enable: bool,
+    //ALERT output pin
+    pub alert_pin_enable: bool,
+    //ALERT pin active high
+    pub polarity: bool,
+}
+
+impl Config {
+    const ALERT_HOLD: u8 = 0x10;
+    const ALERT_FLAG_ENABLE: u8 = 0x08;
+    const ALERT_PIN_ENABLE: u8 = 0x04;
+    const RESERVED: u8 = 0x02;
+    const POLARITY: u8 = 0x01;
+
+    pub fn encode(&self) -> u8 {
+        let mut value = (self.cycle_time as u8) << 5;
+        if self.alert_hold {
+            value |= Config::ALERT_HOLD;
+        }
+        if self.alert_flag_enable {
+            value |= Config::ALERT_FLAG_ENABLE;
+        }
+        if self.alert_pin_enable {
+            value |= Config::ALERT_PIN_ENABLE;
+        }
+        if self.polarity {
+            value |= Config::POLARITY;
+        }
+        value
+    }
+
+    pub fn decode(value: u8) -> Result<Config> {
+        if value & Config::RESERVED != 0 {
+            return Err(Error::Register { addr: 0x02, value: value as u16 });
+        }
+        Ok(Config {
+            cycle_time: CycleTime::from_bits(value >> 5),
+            alert_hold: value & Config::ALERT_HOLD != 0,
+            alert_flag_enable: value & Config::ALERT_FLAG_ENABLE != 0,
+            alert_pin_enable: value & Config::ALERT_PIN_ENABLE != 0,
+            polarity: value & Config::POLARITY != 0,
+        })
+    }
+}
+
+impl ADC<I2c<File>> {
+
+    //New ADC in default bus and address
     pub fn new() -> Result<ADC> {
-        let dev: I2c<std::fs::File> = I2c::from_path("/dev/i2c-2")?;
-    
-        Ok(ADC{dev: dev})
+        ADC::open(BUS_PATH, SLAVE_ADDR)
+    }
+
+    //New ADC in i2c bus "path" (ex: /dev/i2c-2) with 7-bit slave "address"
+    pub fn open<P: AsRef<Path>>(path: P, address: u16) -> Result<ADC> {
+        if !SLAVE_ADDRS.contains(&address) {
+            return Err(Error::Address(address));
+        }
+        let path = path.as_ref();
+        let mut dev: I2c<File> = I2c::from_path(path)
+            .map_err(|err| Error::Open(path.to_path_buf(), err))?;
+        dev.smbus_set_slave_address(address, false)?;
+
+        Ok(ADC::with_bus(dev))
     }
+}
+
+impl<B: Bus> ADC<B> {
 
-    //set conf in ADC. Flags type -> FlagRegister, example (FlagRegister::AlertHold | FlagRegister::AlertPINEnable)
-    pub fn set_conf_register(&mut self, flags: u8) -> Result<()> {
-        self.dev.smbus_write_byte_data(0x02, flags)?;
-        println!("register conf: {:#X}", flags);
-        self.dev.smbus_write_byte_data(0x01, 0x00)?;
+    //New ADC over any register bus (ex: bus::RegisterFile in tests)
+    pub fn with_bus(dev: B) -> ADC<B> {
+        ADC::with_scale(dev, Scale::default())
+    }
+
+    pub fn with_scale(dev: B, scale: Scale) -> ADC<B> {
+        ADC { dev, scale }
+    }
+
+    pub fn scale(&self) -> &Scale {
+        &self.scale
+    }
+
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.scale = scale;
+    }
+
+    pub fn bus(&self) -> &B {
+        &self.dev
+    }
+
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.dev
+    }
+
+    pub fn into_bus(self) -> B {
+        self.dev
+    }
+
+    //set conf in ADC, with "verify" the register is read back and compared
+    pub fn set_config(&mut self, config: &Config, verify: bool) -> Result<()> {
+        let flags = config.encode();
+        self.dev.write_byte_data(0x02, flags)?;
+        debug!("register conf: {:#X}", flags);
+        self.dev.write_byte_data(0x01, 0x00)?;
+        if verify {
+            let found = self.read_register_byte(0x02)?;
+            if found != flags {
+                return Err(Error::Verify { addr: 0x02, expected: flags as u16, found: found as u16 });
+            }
+        }
         Ok(())
     }
 
+    pub fn read_config(&mut self) -> Result<Config> {
+        let result = self.read_register_byte(0x02)?;
+        Config::decode(result)
+    }
+
     pub fn set_alert_under_range(&mut self, value: f32) -> Result<()> {
 
-        let value_u = (value/0.016).round() as u16 & 0x0FFF;    
-        self.dev.smbus_write_word_data(0x03, value_u.to_be())?;
+        let value_u = self.to_code(value)?;
+        self.write_register_word(0x03, value_u)?;
         Ok(())
     }
 
     pub fn set_alert_over_range(&mut self, value: f32) -> Result<()> {
 
-        let value_u = (value/0.016).round() as u16 & 0x0FFF;      
-        self.dev.smbus_write_word_data(0x04, value_u.to_be())?;
+        let value_u = self.to_code(value)?;
+        self.write_register_word(0x04, value_u)?;
         Ok(())
     }
 
     pub fn set_alert_hysteresis(&mut self, value: f32) -> Result<()> {
 
-        let value_u = (value/0.016).round() as u16 & 0x0FFF;
-        self.dev.smbus_write_word_data(0x05, value_u.to_be())?;
+        let value_u = self.to_code_delta(value)?;
+        self.write_register_word(0x05, value_u)?;
         Ok(())
     }
 
@@ -72,12 +296,19 @@ impl ADC {
 
         // let result = read_data.iter().rev().enumerate().fold(0, |acc: u16, (i, x)| acc + (((*x as u16) & 0x00FF)  << i*8 ));
 
-        let result = self.dev.smbus_read_word_data(addr)?;
+        // the ADC sends the MSB first, SMBus words are LSB first
+        let result = self.dev.read_word_data(addr)?.swap_bytes();
         // println!("Reading: {:?}", read_data);
-        println!("Reading: {:#X}", result);
+        trace!("register {:#X}: {:#X}", addr, result);
         Ok(result)
     }
 
+    //16-bit register, MSB first like read_register_word
+    fn write_register_word(&mut self, addr: u8, value: u16) -> Result<()> {
+        self.dev.write_word_data(addr, value.swap_bytes())?;
+        Ok(())
+    }
+
     pub fn read_register_byte(&mut self, addr: u8) -> Result<u8> {
 
         // let mut read_data: [u8; 1] = [0; 1];
@@ -89,39 +320,62 @@ impl ADC {
 
         // let result = read_data[0];
 
-        let result = self.dev.smbus_read_byte_data(addr)?;
+        let result = self.dev.read_byte_data(addr)?;
         // println!("Reading: {:?}", read_data);
-        println!("Reading: {:#X}", result);
+        trace!("register {:#X}: {:#X}", addr, result);
         Ok(result)
     }
  
-    pub fn read_value(&mut self) -> Result<(f32, bool)> {
+    //Result -> (12-bit code, alert flag)
+    pub fn read_raw_value(&mut self) -> Result<(u16, bool)> {
         let result = self.read_register_word(0x00)?;
         // println!("read_value: {:#X}", result);
         let alert = (result & 0x8000) == 0x8000;
-        let value = result & 0x0FFF;
-        Ok(((value as f32) * 0.016, alert))
+        Ok((result & FULL_SCALE, alert))
     }
 
-    pub fn read_min_value(&mut self) -> Result<f32> {
+    pub fn read_value(&mut self) -> Result<(f32, bool)> {
+        let (raw, alert) = self.read_raw_value()?;
+        Ok((self.scale.to_volts(raw), alert))
+    }
 
+    pub fn read_sample(&mut self) -> Result<Sample> {
+        let (raw, alert) = self.read_raw_value()?;
+        Ok(Sample {
+            raw,
+            volts: self.scale.to_volts(raw),
+            alert,
+            timestamp: SystemTime::now(),
+        })
+    }
+
+    pub fn read_raw_min_value(&mut self) -> Result<u16> {
         let result = self.read_register_word(0x06)?;
-        Ok(((result & 0x0FFF) as f32) * 0.016)
+        Ok(result & FULL_SCALE)
+    }
+
+    pub fn read_min_value(&mut self) -> Result<f32> {
+        let raw = self.read_raw_min_value()?;
+        Ok(self.scale.to_volts(raw))
     }
     pub fn write_min_value(&mut self, value: f32) -> Result<()> {
-        let value_u = (value/0.016).round() as u16 & 0x0FFF;
-        self.dev.smbus_write_word_data(0x06, value_u.to_be())?;
+        let value_u = self.to_code(value)?;
+        self.write_register_word(0x06, value_u)?;
         Ok(())
     }
 
-    pub fn read_max_value(&mut self) -> Result<f32> {
-
+    pub fn read_raw_max_value(&mut self) -> Result<u16> {
         let result = self.read_register_word(0x07)?;
-        Ok(((result & 0x0FFF) as f32) * 0.016)
+        Ok(result & FULL_SCALE)
+    }
+
+    pub fn read_max_value(&mut self) -> Result<f32> {
+        let raw = self.read_raw_max_value()?;
+        Ok(self.scale.to_volts(raw))
     }
     pub fn write_max_value(&mut self, value: f32) -> Result<()> {
-        let value_u = (value/0.016).round() as u16 & 0x0FFF;
-        self.dev.smbus_write_word_data(0x07, value_u.to_be())?;
+        let value_u = self.to_code(value)?;
+        self.write_register_word(0x07, value_u)?;
         Ok(())
     }
 
@@ -129,20 +383,137 @@ impl ADC {
     pub fn read_alert(&mut self) -> Result<(bool, bool)> {
 
         let result = self.read_register_byte(0x01)?;
+        if result & 0xFC != 0 {
+            return Err(Error::Register { addr: 0x01, value: result as u16 });
+        }
         Ok((result & 0x02 == 0x02, result & 0x01 == 0x01))
     }
 
     pub fn clear_alert_over(&mut self) -> Result<()> {
-        self.dev.smbus_write_byte_data(0x01, 0x02)?;
+        self.dev.write_byte_data(0x01, 0x02)?;
         Ok(())
     }
     pub fn clear_alert_under(&mut self) -> Result<()> {
-        self.dev.smbus_write_byte_data(0x01, 0x01)?;
+        self.dev.write_byte_data(0x01, 0x01)?;
         Ok(())
     }
     pub fn clear_alerts(&mut self) -> Result<()> {
 
-        self.dev.smbus_write_byte_data(0x01, 0x03)?;
+        self.dev.write_byte_data(0x01, 0x03)?;
         Ok(())
     }
+
+    //volts to 12-bit register value
+    fn to_code(&self, value: f32) -> Result<u16> {
+        self.scale.to_code(value).ok_or(Error::OutOfRange(value))
+    }
+
+    fn to_code_delta(&self, value: f32) -> Result<u16> {
+        self.scale.to_code_delta(value).ok_or(Error::OutOfRange(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::RegisterFile;
+
+    // register content as it travels on the SMBus
+    fn smbus(value: u16) -> u16 {
+        value.swap_bytes()
+    }
+
+    fn adc() -> ADC<RegisterFile> {
+        ADC::with_bus(RegisterFile::new())
+    }
+
+    fn assert_volts(found: f32, expected: f32) {
+        assert!((found - expected).abs() < 1e-4, "{} != {}", found, expected);
+    }
+
+    #[test]
+    fn setters_write_msb_first_codes() {
+        let mut dev = adc();
+        dev.set_alert_under_range(9.6).unwrap();
+        dev.set_alert_over_range(50.0).unwrap();
+        dev.set_alert_hysteresis(0.48).unwrap();
+        assert_eq!(dev.bus().word(0x03), smbus(600));
+        assert_eq!(dev.bus().word(0x04), smbus(3125));
+        assert_eq!(dev.bus().word(0x05), smbus(30));
+        assert_eq!(dev.read_register_word(0x04).unwrap(), 3125);
+    }
+
+    #[test]
+    fn setters_reject_out_of_range() {
+        let mut dev = adc();
+        assert!(matches!(dev.set_alert_over_range(70.0), Err(Error::OutOfRange(_))));
+        assert!(matches!(dev.write_min_value(-1.0), Err(Error::OutOfRange(_))));
+        assert_eq!(dev.bus().word(0x04), 0);
+    }
+
+    #[test]
+    fn read_value_and_alert_flag() {
+        let mut dev = adc();
+        dev.bus_mut().set_word(0x00, smbus(750));
+        let (volts, alert) = dev.read_value().unwrap();
+        assert_volts(volts, 12.0);
+        assert!(!alert);
+
+        dev.bus_mut().set_word(0x00, smbus(0x8000 | 500));
+        let (volts, alert) = dev.read_value().unwrap();
+        assert_volts(volts, 8.0);
+        assert!(alert);
+    }
+
+    #[test]
+    fn raw_codes_are_device_codes() {
+        let mut dev = adc();
+        dev.bus_mut().set_word(0x00, smbus(0x8000 | 0x0ABC));
+        dev.bus_mut().set_word(0x06, smbus(0x0123));
+        dev.bus_mut().set_word(0x07, smbus(0x0FFF));
+        assert_eq!(dev.read_raw_value().unwrap(), (0x0ABC, true));
+        assert_eq!(dev.read_raw_min_value().unwrap(), 0x0123);
+        assert_eq!(dev.read_raw_max_value().unwrap(), 0x0FFF);
+
+        let sample = dev.read_sample().unwrap();
+        assert_eq!(sample.raw, 0x0ABC);
+        assert!(sample.alert);
+        assert_volts(sample.volts, dev.scale().to_volts(0x0ABC));
+    }
+
+    #[test]
+    fn min_max_round_trip() {
+        let mut dev = adc();
+        dev.write_min_value(12.0).unwrap();
+        dev.write_max_value(13.6).unwrap();
+        assert_eq!(dev.bus().word(0x06), smbus(750));
+        assert_volts(dev.read_min_value().unwrap(), 12.0);
+        assert_volts(dev.read_max_value().unwrap(), 13.6);
+
+        // reset values, within one code
+        dev.write_min_value(50.0).unwrap();
+        dev.write_max_value(1.0).unwrap();
+        let lsb = dev.scale().lsb();
+        assert!((dev.read_min_value().unwrap() - 50.0).abs() <= lsb);
+        assert!((dev.read_max_value().unwrap() - 1.0).abs() <= lsb);
+    }
+
+    #[test]
+    fn alert_status_and_config() {
+        let mut dev = adc();
+        dev.bus_mut().set_byte(0x01, 0x02);
+        assert_eq!(dev.read_alert().unwrap(), (true, false));
+        dev.bus_mut().set_byte(0x01, 0x04);
+        assert!(matches!(dev.read_alert(), Err(Error::Register { addr: 0x01, .. })));
+
+        let config = Config {
+            cycle_time: CycleTime::Tx32,
+            alert_hold: false,
+            alert_flag_enable: true,
+            alert_pin_enable: true,
+            polarity: false,
+        };
+        dev.set_config(&config, true).unwrap();
+        assert_eq!(dev.read_config().unwrap(), config);
+    }
 }
@@ -0,0 +1,288 @@
+// Sources of the ADC ALERT line: an input device (evdev key events), a GPIO
+// character device line, a mock fed by a channel (tests), or none, the alerts
+// are then read from the ADC registers by the poll loop.
+
+use std::fmt;
+use std::future;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use evdev::{EventStream, InputEventKind};
+use gpio_cdev::{Chip, EventRequestFlags, LineEventHandle, LineRequestFlags};
+use log::warn;
+use tokio::sync::mpsc;
+
+use crate::input::{self, KeyMap};
+use crate::payload::AlertKind;
+use crate::settings::{AlertInput, GpioSettings, Settings};
+
+const CONSUMER: &str = "volt";
+
+// alert raised or cleared by the ALERT line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlertEvent {
+    pub kind: AlertKind,
+    pub active: bool,
+}
+
+pub enum AlertSource {
+    Evdev {
+        events: Box<EventStream>,
+        keymap: KeyMap,
+        name: String,
+    },
+    Gpio {
+        rx: mpsc::UnboundedReceiver<AlertEvent>,
+        name: String,
+    },
+    Mock(mpsc::UnboundedReceiver<AlertEvent>),
+    //alerts read from the ADC registers
+    Registers,
+}
+
+impl AlertSource {
+    // open the alert.source input, Registers when the device is missing
+    pub fn open(settings: &Settings) -> io::Result<AlertSource> {
+        let result = match settings.alert.source {
+            AlertInput::Evdev => open_evdev(settings),
+            AlertInput::Gpio => open_gpio(&settings.gpio),
+            AlertInput::Registers => return Ok(AlertSource::Registers),
+        };
+        match result {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                warn!("{}, polling the ADC alert registers", err);
+                Ok(AlertSource::Registers)
+            }
+            result => result,
+        }
+    }
+
+    //source fed by the returned sender
+    pub fn mock() -> (mpsc::UnboundedSender<AlertEvent>, AlertSource) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (tx, AlertSource::Mock(rx))
+    }
+
+    //true when the alerts must be polled from the ADC registers
+    pub fn is_polled(&self) -> bool {
+        matches!(self, AlertSource::Registers)
+    }
+
+    //next alert change, never ready for Registers
+    pub async fn next(&mut self) -> io::Result<AlertEvent> {
+        match self {
+            AlertSource::Evdev { events, keymap, .. } => loop {
+                let event = events.next_event().await?;
+                if let InputEventKind::Key(key) = event.kind() {
+                    if let Some((kind, active)) = keymap.alert(key, event.value()) {
+                        return Ok(AlertEvent { kind, active });
+                    }
+                }
+            },
+            AlertSource::Gpio { rx, .. } | AlertSource::Mock(rx) => match rx.recv().await {
+                Some(event) => Ok(event),
+                None => future::pending().await,
+            },
+            AlertSource::Registers => future::pending().await,
+        }
+    }
+}
+
+impl fmt::Display for AlertSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AlertSource::Evdev { name, .. } => write!(f, "input device {}", name),
+            AlertSource::Gpio { name, .. } => write!(f, "GPIO {}", name),
+            AlertSource::Mock(_) => write!(f, "mock"),
+            AlertSource::Registers => write!(f, "ADC registers"),
+        }
+    }
+}
+
+fn open_evdev(settings: &Settings) -> io::Result<AlertSource> {
+    let keymap = KeyMap::new(&settings.evdev.keys).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let device = input::open(&settings.evdev)?;
+    for key in keymap.unsupported(&device) {
+        warn!("alert input doesn't report {:?}", key);
+    }
+    let name = format!(
+        "{:?}, phys: {:?}",
+        device.name().unwrap_or("unknown"),
+        device.physical_path().unwrap_or("unknown")
+    );
+    Ok(AlertSource::Evdev {
+        events: Box::new(device.into_event_stream()?),
+        keymap,
+        name,
+    })
+}
+
+fn open_gpio(settings: &GpioSettings) -> io::Result<AlertSource> {
+    if !Path::new(&settings.chip).exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("GPIO chip {} not found", settings.chip),
+        ));
+    }
+    let error = |err: gpio_cdev::Error| {
+        io::Error::other(format!("GPIO {} line {}: {}", settings.chip, settings.line, err))
+    };
+    let mut chip = Chip::new(&settings.chip).map_err(error)?;
+    let mut flags = LineRequestFlags::INPUT;
+    if settings.active_low {
+        flags |= LineRequestFlags::ACTIVE_LOW;
+    }
+    let events = chip
+        .get_line(settings.line)
+        .and_then(|line| line.events(flags, EventRequestFlags::BOTH_EDGES, CONSUMER))
+        .map_err(error)?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (kind, debounce) = (settings.alert, Duration::from_millis(settings.debounce));
+    thread::spawn(move || watch_line(events, kind, debounce, tx));
+    Ok(AlertSource::Gpio {
+        rx,
+        name: format!("{} line {}", settings.chip, settings.line),
+    })
+}
+
+// GPIO line read by watch_line
+trait LineEvents {
+    //active state, the ACTIVE_LOW flag is applied by the kernel
+    fn value(&mut self) -> io::Result<bool>;
+    //block until the next edge
+    fn wait_edge(&mut self) -> io::Result<()>;
+}
+
+impl LineEvents for LineEventHandle {
+    fn value(&mut self) -> io::Result<bool> {
+        self.get_value().map(|value| value != 0).map_err(io::Error::other)
+    }
+
+    fn wait_edge(&mut self) -> io::Result<()> {
+        self.get_event().map(|_| ()).map_err(io::Error::other)
+    }
+}
+
+// report the line state after each edge once it's stable for "debounce",
+// ends when the receiver is dropped
+fn watch_line<L: LineEvents>(mut events: L, kind: AlertKind, debounce: Duration, tx: mpsc::UnboundedSender<AlertEvent>) {
+    let mut last = false;
+    while !tx.is_closed() {
+        match events.value() {
+            Ok(value) if value != last => {
+                last = value;
+                if tx.send(AlertEvent { kind, active: last }).is_err() {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(err) => warn!("GPIO read error: {}", err),
+        }
+        if let Err(err) = events.wait_edge() {
+            warn!("GPIO event error: {}", err);
+            thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+        thread::sleep(debounce);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc as std_mpsc;
+
+    use super::*;
+
+    const DEBOUNCE: Duration = Duration::from_millis(2);
+
+    // line taking the last value of each burst, every value of the burst is an edge
+    struct MockLine {
+        value: bool,
+        pending: usize,
+        bursts: std_mpsc::Receiver<Vec<bool>>,
+    }
+
+    impl LineEvents for MockLine {
+        fn value(&mut self) -> io::Result<bool> {
+            Ok(self.value)
+        }
+
+        fn wait_edge(&mut self) -> io::Result<()> {
+            if self.pending > 0 {
+                self.pending -= 1;
+                return Ok(());
+            }
+            let burst = self
+                .bursts
+                .recv()
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "line closed"))?;
+            self.value = *burst.last().unwrap();
+            self.pending = burst.len() - 1;
+            Ok(())
+        }
+    }
+
+    fn watch(initial: bool) -> (std_mpsc::Sender<Vec<bool>>, mpsc::UnboundedReceiver<AlertEvent>) {
+        let (bursts, rx) = std_mpsc::channel();
+        let line = MockLine {
+            value: initial,
+            pending: 0,
+            bursts: rx,
+        };
+        let (tx, events) = mpsc::unbounded_channel();
+        thread::spawn(move || watch_line(line, AlertKind::Over, DEBOUNCE, tx));
+        (bursts, events)
+    }
+
+    fn over(active: bool) -> Option<AlertEvent> {
+        Some(AlertEvent {
+            kind: AlertKind::Over,
+            active,
+        })
+    }
+
+    #[test]
+    fn bouncing_edges_report_once() {
+        let (bursts, mut events) = watch(false);
+        bursts.send(vec![true, false, true, false, true]).unwrap();
+        assert_eq!(events.blocking_recv(), over(true));
+        bursts.send(vec![false, true, false]).unwrap();
+        assert_eq!(events.blocking_recv(), over(false));
+    }
+
+    #[test]
+    fn glitches_are_ignored() {
+        let (bursts, mut events) = watch(false);
+        bursts.send(vec![true, false]).unwrap();
+        bursts.send(vec![true, false, false]).unwrap();
+        bursts.send(vec![true]).unwrap();
+        assert_eq!(events.blocking_recv(), over(true));
+        bursts.send(vec![false, true]).unwrap();
+        bursts.send(vec![false]).unwrap();
+        assert_eq!(events.blocking_recv(), over(false));
+    }
+
+    #[test]
+    fn active_line_at_start() {
+        let (bursts, mut events) = watch(true);
+        assert_eq!(events.blocking_recv(), over(true));
+        bursts.send(vec![false]).unwrap();
+        assert_eq!(events.blocking_recv(), over(false));
+    }
+
+    #[test]
+    fn mock_source() {
+        let (tx, mut source) = AlertSource::mock();
+        assert!(!source.is_polled());
+        tx.send(AlertEvent {
+            kind: AlertKind::Under,
+            active: true,
+        })
+        .unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let event = runtime.block_on(source.next()).unwrap();
+        assert_eq!((event.kind, event.active), (AlertKind::Under, true));
+    }
+}
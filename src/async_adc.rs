@@ -0,0 +1,285 @@
+use std::fs::File;
+use std::sync::mpsc as std_mpsc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use i2c_linux::I2c;
+use tokio::sync::oneshot;
+use tokio::time::{self, Duration};
+
+use crate::adc::{Config, Error, Result, Sample, ADC};
+use crate::bus::Bus;
+
+// operations waiting behind the running one, more fail with Error::Busy
+const QUEUE_SIZE: usize = 2;
+
+type Job<B> = Box<dyn FnOnce(&mut ADC<B>) + Send>;
+
+enum Worker<B: Bus> {
+    Thread(std_mpsc::SyncSender<Job<B>>),
+    //operations run in the calling task
+    Inline(Arc<Mutex<ADC<B>>>),
+}
+
+// Async handle to an ADC owned by a dedicated worker thread. Blocking SMBus
+// transfers run in the worker, callers only wait (with a timeout) for the reply,
+// so a stuck bus never blocks the tokio runtime.
+pub struct AsyncAdc<B: Bus = I2c<File>> {
+    worker: Worker<B>,
+    timeout: Duration,
+}
+
+impl<B: Bus> Clone for AsyncAdc<B> {
+    fn clone(&self) -> Self {
+        let worker = match &self.worker {
+            Worker::Thread(jobs) => Worker::Thread(jobs.clone()),
+            Worker::Inline(adc) => Worker::Inline(adc.clone()),
+        };
+        AsyncAdc {
+            worker,
+            timeout: self.timeout,
+        }
+    }
+}
+
+//worker thread serving the ADC returned by "open", it ends when all the senders are dropped
+fn spawn_worker<B, F>(open: F) -> std_mpsc::SyncSender<Job<B>>
+where
+    B: Bus + Send + 'static,
+    F: FnOnce() -> Option<ADC<B>> + Send + 'static,
+{
+    let (jobs, queue) = std_mpsc::sync_channel::<Job<B>>(QUEUE_SIZE);
+    thread::Builder::new()
+        .name("adc".to_string())
+        .spawn(move || {
+            if let Some(mut adc) = open() {
+                while let Ok(job) = queue.recv() {
+                    job(&mut adc);
+                }
+            }
+        })
+        .expect("can't spawn ADC worker thread");
+    jobs
+}
+
+impl<B: Bus + Send + 'static> AsyncAdc<B> {
+    //spawn the worker thread, "timeout" is the default per-operation timeout
+    pub fn spawn(adc: ADC<B>, timeout: Duration) -> AsyncAdc<B> {
+        let jobs = spawn_worker(move || Some(adc));
+        AsyncAdc {
+            worker: Worker::Thread(jobs),
+            timeout,
+        }
+    }
+
+    //no worker, the operations run in the calling task without timeout. For
+    //buses that never block (ex: bus::RegisterFile), works with tokio paused time.
+    pub fn inline(adc: ADC<B>) -> AsyncAdc<B> {
+        AsyncAdc {
+            worker: Worker::Inline(Arc::new(Mutex::new(adc))),
+            timeout: Duration::MAX,
+        }
+    }
+
+    //replace the ADC with the one returned by "open", run in a new worker so
+    //a worker stuck in the bus is left behind. Clones keep the previous ADC.
+    pub async fn reopen<F>(&mut self, open: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<ADC<B>> + Send + 'static,
+    {
+        if let Worker::Inline(adc) = &self.worker {
+            let dev = open()?;
+            *adc.lock().unwrap_or_else(PoisonError::into_inner) = dev;
+            return Ok(());
+        }
+        let (tx, rx) = oneshot::channel();
+        let jobs = spawn_worker(move || match open() {
+            Ok(adc) => tx.send(Ok(())).ok().map(|_| adc),
+            Err(err) => {
+                let _ = tx.send(Err(err));
+                None
+            }
+        });
+        match time::timeout(self.timeout, rx).await {
+            Ok(Ok(Ok(()))) => {
+                self.worker = Worker::Thread(jobs);
+                Ok(())
+            }
+            Ok(Ok(Err(err))) => Err(err),
+            Ok(Err(_)) => Err(Error::Closed),
+            Err(_) => Err(Error::Timeout(self.timeout)),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    //run "f" in the worker with the default timeout
+    pub async fn call<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut ADC<B>) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.call_timeout(self.timeout, f).await
+    }
+
+    //run "f" in the worker, the worker keeps running "f" after a timeout,
+    //the next operations wait behind it until the queue is full
+    pub async fn call_timeout<F, T>(&self, timeout: Duration, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut ADC<B>) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let jobs = match &self.worker {
+            Worker::Thread(jobs) => jobs,
+            Worker::Inline(adc) => return f(&mut adc.lock().unwrap_or_else(PoisonError::into_inner)),
+        };
+        let (tx, rx) = oneshot::channel();
+        let job: Job<B> = Box::new(move |adc| {
+            let _ = tx.send(f(adc));
+        });
+        jobs.try_send(job).map_err(|err| match err {
+            std_mpsc::TrySendError::Full(_) => Error::Busy,
+            std_mpsc::TrySendError::Disconnected(_) => Error::Closed,
+        })?;
+        match time::timeout(timeout, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(Error::Closed),
+            Err(_) => Err(Error::Timeout(timeout)),
+        }
+    }
+
+    pub async fn set_config(&self, config: Config, verify: bool) -> Result<()> {
+        self.call(move |adc| adc.set_config(&config, verify)).await
+    }
+
+    pub async fn read_config(&self) -> Result<Config> {
+        self.call(|adc| adc.read_config()).await
+    }
+
+    pub async fn set_alert_under_range(&self, value: f32) -> Result<()> {
+        self.call(move |adc| adc.set_alert_under_range(value)).await
+    }
+
+    pub async fn set_alert_over_range(&self, value: f32) -> Result<()> {
+        self.call(move |adc| adc.set_alert_over_range(value)).await
+    }
+
+    pub async fn set_alert_hysteresis(&self, value: f32) -> Result<()> {
+        self.call(move |adc| adc.set_alert_hysteresis(value)).await
+    }
+
+    pub async fn read_value(&self) -> Result<(f32, bool)> {
+        self.call(|adc| adc.read_value()).await
+    }
+
+    pub async fn read_sample(&self) -> Result<Sample> {
+        self.call(|adc| adc.read_sample()).await
+    }
+
+    pub async fn read_min_value(&self) -> Result<f32> {
+        self.call(|adc| adc.read_min_value()).await
+    }
+
+    pub async fn write_min_value(&self, value: f32) -> Result<()> {
+        self.call(move |adc| adc.write_min_value(value)).await
+    }
+
+    pub async fn read_max_value(&self) -> Result<f32> {
+        self.call(|adc| adc.read_max_value()).await
+    }
+
+    pub async fn write_max_value(&self, value: f32) -> Result<()> {
+        self.call(move |adc| adc.write_max_value(value)).await
+    }
+
+    pub async fn read_alert(&self) -> Result<(bool, bool)> {
+        self.call(|adc| adc.read_alert()).await
+    }
+
+    pub async fn clear_alerts(&self) -> Result<()> {
+        self.call(|adc| adc.clear_alerts()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::bus::RegisterFile;
+
+    // register file hanging each transfer while "stuck" is set
+    struct Hanging {
+        registers: RegisterFile,
+        stuck: Arc<AtomicBool>,
+    }
+
+    impl Hanging {
+        fn wait(&self) {
+            while self.stuck.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+
+    impl Bus for Hanging {
+        fn read_byte_data(&mut self, reg: u8) -> io::Result<u8> {
+            self.wait();
+            self.registers.read_byte_data(reg)
+        }
+
+        fn read_word_data(&mut self, reg: u8) -> io::Result<u16> {
+            self.wait();
+            self.registers.read_word_data(reg)
+        }
+
+        fn write_byte_data(&mut self, reg: u8, value: u8) -> io::Result<()> {
+            self.wait();
+            self.registers.write_byte_data(reg, value)
+        }
+
+        fn write_word_data(&mut self, reg: u8, value: u16) -> io::Result<()> {
+            self.wait();
+            self.registers.write_word_data(reg, value)
+        }
+    }
+
+    fn hanging(stuck: bool) -> (ADC<Hanging>, Arc<AtomicBool>) {
+        let stuck = Arc::new(AtomicBool::new(stuck));
+        let bus = Hanging {
+            registers: RegisterFile::new(),
+            stuck: stuck.clone(),
+        };
+        (ADC::with_bus(bus), stuck)
+    }
+
+    #[tokio::test]
+    async fn stuck_bus_doesnt_queue_jobs_forever() {
+        let (dev, stuck) = hanging(true);
+        let mut adc = AsyncAdc::spawn(dev, Duration::from_millis(20));
+        assert!(matches!(adc.read_value().await, Err(Error::Timeout(_))));
+        for _ in 0..QUEUE_SIZE {
+            assert!(matches!(adc.read_value().await, Err(Error::Timeout(_))));
+        }
+        assert!(matches!(adc.read_value().await, Err(Error::Busy)));
+
+        // the new worker doesn't wait behind the stuck one
+        adc.reopen(|| Ok(hanging(false).0)).await.unwrap();
+        adc.write_min_value(12.0).await.unwrap();
+        assert!((adc.read_min_value().await.unwrap() - 12.0).abs() < 1e-4);
+        stuck.store(false, Ordering::SeqCst);
+    }
+
+    #[tokio::test]
+    async fn failed_reopen_keeps_the_adc() {
+        let (dev, _) = hanging(false);
+        let mut adc = AsyncAdc::spawn(dev, Duration::from_millis(100));
+        adc.write_max_value(13.6).await.unwrap();
+        assert!(matches!(adc.reopen(|| Err(Error::Closed)).await, Err(Error::Closed)));
+        assert!((adc.read_max_value().await.unwrap() - 13.6).abs() < 1e-4);
+    }
+}
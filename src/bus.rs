@@ -0,0 +1,88 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use i2c_linux::I2c;
+
+// SMBus register access used by the ADC driver. Words are passed exactly as
+// they travel on the SMBus (LSB first), the driver swaps the bytes of the
+// MSB first ADC registers on reads and writes.
+pub trait Bus {
+    fn read_byte_data(&mut self, reg: u8) -> io::Result<u8>;
+    fn read_word_data(&mut self, reg: u8) -> io::Result<u16>;
+    fn write_byte_data(&mut self, reg: u8, value: u8) -> io::Result<()>;
+    fn write_word_data(&mut self, reg: u8, value: u16) -> io::Result<()>;
+}
+
+// Linux i2c-dev backend
+impl<I: AsRawFd> Bus for I2c<I> {
+    fn read_byte_data(&mut self, reg: u8) -> io::Result<u8> {
+        self.smbus_read_byte_data(reg)
+    }
+
+    fn read_word_data(&mut self, reg: u8) -> io::Result<u16> {
+        self.smbus_read_word_data(reg)
+    }
+
+    fn write_byte_data(&mut self, reg: u8, value: u8) -> io::Result<()> {
+        self.smbus_write_byte_data(reg, value)
+    }
+
+    fn write_word_data(&mut self, reg: u8, value: u16) -> io::Result<()> {
+        self.smbus_write_word_data(reg, value)
+    }
+}
+
+// In-memory register file, lets the driver run without the real board.
+// Every register holds a word, byte accesses use its low byte.
+#[derive(Debug, Clone)]
+pub struct RegisterFile {
+    registers: [u16; 256],
+}
+
+impl RegisterFile {
+    pub fn new() -> Self {
+        RegisterFile { registers: [0; 256] }
+    }
+
+    pub fn byte(&self, reg: u8) -> u8 {
+        self.registers[reg as usize] as u8
+    }
+
+    pub fn word(&self, reg: u8) -> u16 {
+        self.registers[reg as usize]
+    }
+
+    pub fn set_byte(&mut self, reg: u8, value: u8) {
+        self.registers[reg as usize] = value as u16;
+    }
+
+    pub fn set_word(&mut self, reg: u8, value: u16) {
+        self.registers[reg as usize] = value;
+    }
+}
+
+impl Default for RegisterFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for RegisterFile {
+    fn read_byte_data(&mut self, reg: u8) -> io::Result<u8> {
+        Ok(self.byte(reg))
+    }
+
+    fn read_word_data(&mut self, reg: u8) -> io::Result<u16> {
+        Ok(self.word(reg))
+    }
+
+    fn write_byte_data(&mut self, reg: u8, value: u8) -> io::Result<()> {
+        self.set_byte(reg, value);
+        Ok(())
+    }
+
+    fn write_word_data(&mut self, reg: u8, value: u16) -> io::Result<()> {
+        self.set_word(reg, value);
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::scale::Scale;
+
+pub const CALIBRATION_PATH: &str = "/etc/volt/calibration.toml";
+
+// Per-unit gain/offset correction: volts = gain * nominal + offset
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    pub gain: f32,
+    pub offset: f32,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration { gain: 1.0, offset: 0.0 }
+    }
+}
+
+impl Calibration {
+    //points: (nominal measured volts, reference volts).
+    //One point corrects the gain only, two points gain and offset.
+    pub fn from_points(points: &[(f32, f32)]) -> Result<Calibration, String> {
+        let calibration = match *points {
+            [(measured, reference)] => {
+                if measured <= 0.0 {
+                    return Err(format!("invalid measured value: {}", measured));
+                }
+                Calibration { gain: reference / measured, offset: 0.0 }
+            }
+            [(measured_a, reference_a), (measured_b, reference_b)] => {
+                if (measured_b - measured_a).abs() < f32::EPSILON {
+                    return Err(format!("measured values are equal: {}", measured_a));
+                }
+                let gain = (reference_b - reference_a) / (measured_b - measured_a);
+                Calibration { gain, offset: reference_a - gain * measured_a }
+            }
+            _ => return Err(format!("one or two points are needed, got {}", points.len())),
+        };
+        if !calibration.gain.is_finite() || calibration.gain <= 0.0 {
+            return Err(format!("invalid gain: {}", calibration.gain));
+        }
+        Ok(calibration)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Calibration, Box<dyn Error>> {
+        let data = fs::read_to_string(path)?;
+        Ok(toml::from_str(&data)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn apply(&self, scale: Scale) -> Scale {
+        scale.with_correction(self.gain, self.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-4, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn one_point() {
+        let calibration = Calibration::from_points(&[(12.0, 12.24)]).unwrap();
+        assert_close(calibration.gain, 1.02);
+        assert_eq!(calibration.offset, 0.0);
+        assert!(Calibration::from_points(&[(0.0, 12.0)]).is_err());
+        assert!(Calibration::from_points(&[(-1.0, 12.0)]).is_err());
+    }
+
+    #[test]
+    fn two_points() {
+        let calibration = Calibration::from_points(&[(10.0, 10.3), (20.0, 20.5)]).unwrap();
+        assert_close(calibration.gain, 1.02);
+        assert_close(calibration.offset, 0.1);
+        //either order
+        assert_eq!(
+            Calibration::from_points(&[(20.0, 20.5), (10.0, 10.3)]).unwrap(),
+            calibration
+        );
+        assert!(Calibration::from_points(&[(10.0, 10.3), (10.0, 20.5)]).is_err());
+    }
+
+    #[test]
+    fn invalid_gain() {
+        //reference decreasing while the measured value increases
+        assert!(Calibration::from_points(&[(10.0, 20.0), (20.0, 10.0)]).is_err());
+        assert!(Calibration::from_points(&[(10.0, 10.0), (20.0, 10.0)]).is_err());
+        assert!(Calibration::from_points(&[(12.0, -12.0)]).is_err());
+        assert!(Calibration::from_points(&[(12.0, f32::NAN)]).is_err());
+        assert!(Calibration::from_points(&[(12.0, f32::INFINITY)]).is_err());
+        assert!(Calibration::from_points(&[]).is_err());
+        assert!(Calibration::from_points(&[(1.0, 1.0), (2.0, 2.0), (3.0, 3.0)]).is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = std::env::temp_dir().join(format!("volt-calibration-{}", std::process::id()));
+        let path = dir.join("calibration.toml");
+        let _ = fs::remove_dir_all(&dir);
+        let calibration = Calibration { gain: 1.0123, offset: -0.25 };
+        //parent directories created
+        calibration.save(&path).unwrap();
+        assert_eq!(Calibration::load(&path).unwrap(), calibration);
+        fs::write(&path, "gain = 1.5").unwrap();
+        assert!(Calibration::load(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(Calibration::load(&path).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+// Requests received on mqtt.command_topic, JSON objects with the command name
+// in "command" and an optional "id" (any JSON value) copied in the response, ex:
+//
+// {"id": "42", "command": "set_thresholds", "under_range": 9.0, "over_range": 50.0, "hysteresis": 1.0}
+// {"id": 7, "command": "read"}
+// {"command": "set_hysteresis", "hysteresis": 0.5}
+// {"command": "set_publish_interval", "interval": 30}
+// {"command": "reset_min_max"}
+// {"command": "read"}
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    #[serde(default)]
+    pub id: Option<Value>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    //alert thresholds and hysteresis (volts), the missing ones are kept.
+    //Nothing is changed if one of the values is rejected.
+    SetThresholds {
+        #[serde(default)]
+        under_range: Option<f32>,
+        #[serde(default)]
+        over_range: Option<f32>,
+        #[serde(default)]
+        hysteresis: Option<f32>,
+    },
+    //volts
+    SetHysteresis { hysteresis: f32 },
+    //min secs between current_volt messages
+    SetPublishInterval { interval: u64 },
+    //restart the lowest/highest tracking
+    ResetMinMax,
+    //reply with the current, lowest and highest voltage
+    Read,
+}
+
+// request that can't be parsed, "id" is kept for the response when present
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invalid {
+    pub id: Option<Value>,
+    pub error: String,
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::SetThresholds { .. } => "set_thresholds",
+            Command::SetHysteresis { .. } => "set_hysteresis",
+            Command::SetPublishInterval { .. } => "set_publish_interval",
+            Command::ResetMinMax => "reset_min_max",
+            Command::Read => "read",
+        }
+    }
+}
+
+impl Request {
+    pub fn from_json(data: &[u8]) -> Result<Request, Invalid> {
+        let value: Value = serde_json::from_slice(data).map_err(|err| Invalid {
+            id: None,
+            error: format!("invalid JSON: {}", err),
+        })?;
+        let id = value.get("id").filter(|id| !id.is_null()).cloned();
+        serde_json::from_value(value).map_err(|err| Invalid {
+            id,
+            error: format!("invalid request: {}", err),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn invalid(data: &str) -> Invalid {
+        Request::from_json(data.as_bytes()).unwrap_err()
+    }
+
+    #[test]
+    fn commands() {
+        let request = Request::from_json(br#"{"id": "42", "command": "set_thresholds", "under_range": 9.0}"#).unwrap();
+        assert_eq!(request.id, Some(json!("42")));
+        assert_eq!(
+            request.command,
+            Command::SetThresholds {
+                under_range: Some(9.0),
+                over_range: None,
+                hysteresis: None
+            }
+        );
+        for (data, command) in [
+            (r#"{"command": "set_hysteresis", "hysteresis": 0.5}"#, Command::SetHysteresis { hysteresis: 0.5 }),
+            (r#"{"command": "set_publish_interval", "interval": 30}"#, Command::SetPublishInterval { interval: 30 }),
+            (r#"{"command": "reset_min_max"}"#, Command::ResetMinMax),
+            (r#"{"command": "read", "id": null}"#, Command::Read),
+        ] {
+            let request = Request::from_json(data.as_bytes()).unwrap();
+            assert_eq!((request.id, request.command), (None, command));
+        }
+    }
+
+    #[test]
+    fn any_json_id() {
+        for id in [json!(7), json!(-1.5), json!("7"), json!(["a", 1]), json!({"seq": 3})] {
+            let data = json!({"id": id, "command": "read"}).to_string();
+            let request = Request::from_json(data.as_bytes()).unwrap();
+            assert_eq!(request.id, Some(id));
+            assert_eq!(request.command, Command::Read);
+        }
+    }
+
+    #[test]
+    fn invalid_requests_keep_the_id() {
+        let err = invalid(r#"{"id": 7, "command": "reboot"}"#);
+        assert_eq!(err.id, Some(json!(7)));
+        assert!(err.error.starts_with("invalid request: unknown variant `reboot`"), "{}", err.error);
+
+        let err = invalid(r#"{"id": "a", "command": "set_hysteresis"}"#);
+        assert_eq!(err.id, Some(json!("a")));
+        assert!(err.error.contains("missing field `hysteresis`"), "{}", err.error);
+
+        let err = invalid(r#"{"id": 7, "command": "read""#);
+        assert_eq!(err.id, None);
+        assert!(err.error.starts_with("invalid JSON: "), "{}", err.error);
+    }
+}
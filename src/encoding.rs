@@ -0,0 +1,504 @@
+// Wire encodings of the MQTT payloads, selected per topic in the settings
+// (mqtt.readings_encoding, mqtt.events_encoding, mqtt.status_encoding).
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::payload::{Alert, AlertKind, Body, Payload, Reading, Response, State, StatusInfo};
+use crate::proto;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Cbor,
+    //proto/volt.proto
+    Protobuf,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Json(serde_json::Error),
+    Cbor(ciborium::de::Error<io::Error>),
+    Protobuf(prost::DecodeError),
+    //decoded protobuf message without a valid body
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Json(err) => write!(f, "invalid JSON payload: {}", err),
+            Error::Cbor(err) => write!(f, "invalid CBOR payload: {}", err),
+            Error::Protobuf(err) => write!(f, "invalid protobuf payload: {}", err),
+            Error::Invalid(message) => write!(f, "invalid protobuf payload: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Json(err) => Some(err),
+            Error::Cbor(err) => Some(err),
+            Error::Protobuf(err) => Some(err),
+            Error::Invalid(_) => None,
+        }
+    }
+}
+
+impl Encoding {
+    pub fn encode(&self, payload: &Payload) -> Vec<u8> {
+        match self {
+            Encoding::Json => payload.to_json().into_bytes(),
+            Encoding::Cbor => {
+                let mut data = Vec::new();
+                ciborium::ser::into_writer(payload, &mut data).expect("payload serialization");
+                data
+            }
+            Encoding::Protobuf => prost::Message::encode_to_vec(&to_proto(payload)),
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<Payload, Error> {
+        match self {
+            Encoding::Json => serde_json::from_slice(data).map_err(Error::Json),
+            Encoding::Cbor => ciborium::de::from_reader(data).map_err(Error::Cbor),
+            Encoding::Protobuf => {
+                let message: proto::Payload = prost::Message::decode(data).map_err(Error::Protobuf)?;
+                from_proto(message)
+            }
+        }
+    }
+}
+
+fn to_proto(payload: &Payload) -> proto::Payload {
+    use proto::payload::Body as ProtoBody;
+
+    let body = match &payload.body {
+        Body::CurrentVolt(value) => ProtoBody::CurrentVolt(*value),
+        Body::LowestVolt(value) => ProtoBody::LowestVolt(*value),
+        Body::HighestVolt(value) => ProtoBody::HighestVolt(*value),
+        Body::AlertStatusVolt(alert) => ProtoBody::AlertStatusVolt(proto::Alert {
+            value: alert.value,
+            active: alert.active,
+            kind: match alert.kind {
+                AlertKind::Under => proto::AlertKind::Under,
+                AlertKind::Over => proto::AlertKind::Over,
+            } as i32,
+        }),
+        Body::Status(status) => ProtoBody::Status(proto::Status {
+            state: match status.state {
+                State::Online => proto::State::Online,
+                State::Offline => proto::State::Offline,
+            } as i32,
+            version: status.version.clone(),
+            config: status.config.as_ref().map(|config| config.to_string()),
+            clean: status.clean,
+        }),
+        Body::Response(response) => ProtoBody::Response(proto::Response {
+            id: match &response.id {
+                Some(Value::String(id)) => Some(id.clone()),
+                _ => None,
+            },
+            id_json: match &response.id {
+                Some(Value::String(_)) | None => None,
+                Some(id) => Some(id.to_string()),
+            },
+            command: response.command.clone(),
+            ok: response.ok,
+            error: response.error.clone(),
+            reading: response.reading.map(|reading| proto::Reading {
+                current: reading.current,
+                min: reading.min,
+                max: reading.max,
+            }),
+        }),
+    };
+    proto::Payload {
+        schema: payload.schema,
+        time_stamp: payload.time_stamp,
+        body: Some(body),
+    }
+}
+
+fn from_proto(message: proto::Payload) -> Result<Payload, Error> {
+    use proto::payload::Body as ProtoBody;
+
+    let body = match message.body {
+        Some(ProtoBody::CurrentVolt(value)) => Body::CurrentVolt(value),
+        Some(ProtoBody::LowestVolt(value)) => Body::LowestVolt(value),
+        Some(ProtoBody::HighestVolt(value)) => Body::HighestVolt(value),
+        Some(ProtoBody::AlertStatusVolt(alert)) => Body::AlertStatusVolt(Alert {
+            value: alert.value,
+            active: alert.active,
+            kind: match proto::AlertKind::try_from(alert.kind) {
+                Ok(proto::AlertKind::Under) => AlertKind::Under,
+                Ok(proto::AlertKind::Over) => AlertKind::Over,
+                _ => return Err(Error::Invalid(format!("unknown alert kind {}", alert.kind))),
+            },
+        }),
+        Some(ProtoBody::Status(status)) => Body::Status(StatusInfo {
+            state: match proto::State::try_from(status.state) {
+                Ok(proto::State::Online) => State::Online,
+                Ok(proto::State::Offline) => State::Offline,
+                _ => return Err(Error::Invalid(format!("unknown status state {}", status.state))),
+            },
+            version: status.version,
+            config: match status.config {
+                Some(config) => Some(serde_json::from_str(&config).map_err(Error::Json)?),
+                None => None,
+            },
+            clean: status.clean,
+        }),
+        Some(ProtoBody::Response(response)) => Body::Response(Response {
+            id: match (response.id, response.id_json) {
+                (Some(id), _) => Some(Value::String(id)),
+                (None, Some(id)) => Some(serde_json::from_str(&id).map_err(Error::Json)?),
+                (None, None) => None,
+            },
+            command: response.command,
+            ok: response.ok,
+            error: response.error,
+            reading: response.reading.map(|reading| Reading {
+                current: reading.current,
+                min: reading.min,
+                max: reading.max,
+            }),
+        }),
+        None => return Err(Error::Invalid("missing body".to_string())),
+    };
+    Ok(Payload {
+        schema: message.schema,
+        time_stamp: message.time_stamp,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Json, Encoding::Cbor, Encoding::Protobuf];
+
+    fn payloads() -> Vec<Payload> {
+        let reading = Reading {
+            current: 12.0,
+            min: 11.0,
+            max: 13.0,
+        };
+        let bodies = vec![
+            Body::CurrentVolt(12.25),
+            Body::LowestVolt(11.5),
+            Body::HighestVolt(13.75),
+            Body::AlertStatusVolt(Alert {
+                value: 9.25,
+                active: true,
+                kind: AlertKind::Under,
+            }),
+            Body::AlertStatusVolt(Alert {
+                value: 49.0,
+                active: false,
+                kind: AlertKind::Over,
+            }),
+            Body::Status(StatusInfo {
+                state: State::Online,
+                version: Some("1.0.0".to_string()),
+                config: Some(json!({"alert": {"under_range": 9.5, "source": "gpio"}})),
+                clean: None,
+            }),
+            Body::Status(StatusInfo {
+                state: State::Offline,
+                version: None,
+                config: None,
+                clean: Some(true),
+            }),
+            Body::Response(Response::new(Some(json!("7")), Some("read"), Ok(Some(reading)))),
+            Body::Response(Response::new(None, Some("reset_min_max"), Ok(None))),
+            Body::Response(Response::new(Some(json!(7)), Some("read"), Err("busy".to_string()))),
+            Body::Response(Response::new(Some(json!({"seq": [1, 2]})), Some("read"), Ok(None))),
+            Body::Response(Response::new(None, None, Err("invalid request".to_string()))),
+        ];
+        let mut payloads: Vec<Payload> = bodies.into_iter().map(|body| Payload::new(1_600_000_000.5, body)).collect();
+        //Last Will
+        payloads.push(Payload {
+            schema: crate::payload::SCHEMA_VERSION,
+            time_stamp: None,
+            body: Body::Status(StatusInfo {
+                state: State::Offline,
+                version: None,
+                config: None,
+                clean: Some(false),
+            }),
+        });
+        payloads
+    }
+
+    #[test]
+    fn round_trip() {
+        for encoding in ENCODINGS {
+            for payload in payloads() {
+                let data = encoding.encode(&payload);
+                assert_eq!(encoding.decode(&data).unwrap(), payload, "{:?}", encoding);
+            }
+        }
+    }
+
+    #[test]
+    fn garbage() {
+        assert!(matches!(Encoding::Json.decode(b"{\"schema\":"), Err(Error::Json(_))));
+        assert!(matches!(Encoding::Cbor.decode(&[0xff, 0x00]), Err(Error::Cbor(_))));
+        assert!(matches!(Encoding::Protobuf.decode(&[0x0a, 0x05, 0x01]), Err(Error::Protobuf(_))));
+    }
+
+    // field and enum value numbers of proto/volt.proto, by (message, name)
+    fn proto_numbers() -> HashMap<(String, String), u32> {
+        let mut numbers = HashMap::new();
+        let mut block = String::new();
+        for line in include_str!("../proto/volt.proto").lines() {
+            let line = line.split("//").next().unwrap().trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["message", name, "{"] | ["enum", name, "{"] => block = name.to_string(),
+                [.., name, "=", number] if !block.is_empty() => {
+                    let number = number.trim_end_matches(';').parse().unwrap();
+                    numbers.insert((block.clone(), name.to_string()), number);
+                }
+                _ => {}
+            }
+        }
+        numbers
+    }
+
+    // protobuf wire format built from the .proto numbers
+    struct Wire {
+        numbers: HashMap<(String, String), u32>,
+    }
+
+    impl Wire {
+        fn new() -> Self {
+            Wire {
+                numbers: proto_numbers(),
+            }
+        }
+
+        fn number(&self, message: &str, name: &str) -> u32 {
+            match self.numbers.get(&(message.to_string(), name.to_string())) {
+                Some(number) => *number,
+                None => panic!("{}.{} not in volt.proto", message, name),
+            }
+        }
+
+        fn key(&self, message: &str, field: &str, wire_type: u32) -> Vec<u8> {
+            varint((self.number(message, field) << 3 | wire_type) as u64)
+        }
+
+        fn varint(&self, message: &str, field: &str, value: u64) -> Vec<u8> {
+            [self.key(message, field, 0), varint(value)].concat()
+        }
+
+        fn double(&self, message: &str, field: &str, value: f64) -> Vec<u8> {
+            [self.key(message, field, 1), value.to_le_bytes().to_vec()].concat()
+        }
+
+        fn float(&self, message: &str, field: &str, value: f32) -> Vec<u8> {
+            [self.key(message, field, 5), value.to_le_bytes().to_vec()].concat()
+        }
+
+        fn bytes(&self, message: &str, field: &str, value: &[u8]) -> Vec<u8> {
+            [self.key(message, field, 2), varint(value.len() as u64), value.to_vec()].concat()
+        }
+
+        fn header(&self, time_stamp: Option<f64>) -> Vec<u8> {
+            let mut data = self.varint("Payload", "schema", crate::payload::SCHEMA_VERSION as u64);
+            if let Some(time_stamp) = time_stamp {
+                data.extend(self.double("Payload", "time_stamp", time_stamp));
+            }
+            data
+        }
+    }
+
+    fn varint(mut value: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        while value >= 0x80 {
+            data.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        data.push(value as u8);
+        data
+    }
+
+    fn assert_wire(payload: &Payload, data: &[u8]) {
+        assert_eq!(Encoding::Protobuf.encode(payload), data, "{:?}", payload.body);
+        assert_eq!(&Encoding::Protobuf.decode(data).unwrap(), payload);
+    }
+
+    #[test]
+    fn prost_enums_match_proto() {
+        let wire = Wire::new();
+        for (kind, name) in [
+            (proto::AlertKind::Unspecified, "ALERT_KIND_UNSPECIFIED"),
+            (proto::AlertKind::Under, "ALERT_KIND_UNDER"),
+            (proto::AlertKind::Over, "ALERT_KIND_OVER"),
+        ] {
+            assert_eq!(kind as u32, wire.number("AlertKind", name));
+        }
+        for (state, name) in [
+            (proto::State::Unspecified, "STATE_UNSPECIFIED"),
+            (proto::State::Online, "STATE_ONLINE"),
+            (proto::State::Offline, "STATE_OFFLINE"),
+        ] {
+            assert_eq!(state as u32, wire.number("State", name));
+        }
+    }
+
+    #[test]
+    fn protobuf_matches_proto_voltages() {
+        let wire = Wire::new();
+        for (field, value, body) in [
+            ("current_volt", 12.25, Body::CurrentVolt(12.25)),
+            ("lowest_volt", 11.5, Body::LowestVolt(11.5)),
+            ("highest_volt", 13.75, Body::HighestVolt(13.75)),
+        ] {
+            let data = [wire.header(Some(2.5)), wire.float("Payload", field, value)].concat();
+            assert_wire(&Payload::new(2.5, body), &data);
+        }
+    }
+
+    #[test]
+    fn protobuf_matches_proto_alert() {
+        let wire = Wire::new();
+        let alert = [
+            wire.float("Alert", "value", 50.5),
+            wire.varint("Alert", "active", 1),
+            wire.varint("Alert", "kind", wire.number("AlertKind", "ALERT_KIND_OVER") as u64),
+        ]
+        .concat();
+        let data = [wire.header(Some(2.5)), wire.bytes("Payload", "alert_status_volt", &alert)].concat();
+        let body = Body::AlertStatusVolt(Alert {
+            value: 50.5,
+            active: true,
+            kind: AlertKind::Over,
+        });
+        assert_wire(&Payload::new(2.5, body), &data);
+    }
+
+    #[test]
+    fn protobuf_matches_proto_status() {
+        let wire = Wire::new();
+        let config = json!({"publish": {"tick": 3.0}});
+        let status = [
+            wire.varint("Status", "state", wire.number("State", "STATE_ONLINE") as u64),
+            wire.bytes("Status", "version", b"1.0.0"),
+            wire.bytes("Status", "config", config.to_string().as_bytes()),
+        ]
+        .concat();
+        let data = [wire.header(Some(2.5)), wire.bytes("Payload", "status", &status)].concat();
+        let body = Body::Status(StatusInfo {
+            state: State::Online,
+            version: Some("1.0.0".to_string()),
+            config: Some(config),
+            clean: None,
+        });
+        assert_wire(&Payload::new(2.5, body), &data);
+
+        //Last Will: no time_stamp, clean is sent even if false
+        let status = [
+            wire.varint("Status", "state", wire.number("State", "STATE_OFFLINE") as u64),
+            wire.varint("Status", "clean", 0),
+        ]
+        .concat();
+        let data = [wire.header(None), wire.bytes("Payload", "status", &status)].concat();
+        let will = Payload {
+            schema: crate::payload::SCHEMA_VERSION,
+            time_stamp: None,
+            body: Body::Status(StatusInfo {
+                state: State::Offline,
+                version: None,
+                config: None,
+                clean: Some(false),
+            }),
+        };
+        assert_wire(&will, &data);
+    }
+
+    #[test]
+    fn protobuf_matches_proto_response() {
+        let wire = Wire::new();
+        let reading = [
+            wire.float("Reading", "current", 12.0),
+            wire.float("Reading", "min", 11.0),
+            wire.float("Reading", "max", 13.0),
+        ]
+        .concat();
+        let response = [
+            wire.bytes("Response", "id", b"7"),
+            wire.bytes("Response", "command", b"read"),
+            wire.varint("Response", "ok", 1),
+            wire.bytes("Response", "reading", &reading),
+        ]
+        .concat();
+        let data = [wire.header(Some(2.5)), wire.bytes("Payload", "response", &response)].concat();
+        let reading = Reading {
+            current: 12.0,
+            min: 11.0,
+            max: 13.0,
+        };
+        let body = Body::Response(Response::new(Some(json!("7")), Some("read"), Ok(Some(reading))));
+        assert_wire(&Payload::new(2.5, body), &data);
+
+        let response = [
+            wire.bytes("Response", "error", b"busy"),
+            wire.bytes("Response", "id_json", b"7"),
+        ]
+        .concat();
+        let data = [wire.header(Some(2.5)), wire.bytes("Payload", "response", &response)].concat();
+        let body = Body::Response(Response::new(Some(json!(7)), None, Err("busy".to_string())));
+        assert_wire(&Payload::new(2.5, body), &data);
+    }
+
+    fn invalid(data: &[u8]) -> String {
+        match Encoding::Protobuf.decode(data) {
+            Err(Error::Invalid(message)) => message,
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn protobuf_invalid() {
+        let wire = Wire::new();
+        assert_eq!(invalid(&wire.header(Some(2.5))), "missing body");
+
+        for kind in [0, 7] {
+            let alert = wire.varint("Alert", "kind", kind);
+            let data = [wire.header(None), wire.bytes("Payload", "alert_status_volt", &alert)].concat();
+            assert_eq!(invalid(&data), format!("unknown alert kind {}", kind));
+        }
+        for state in [0, 3] {
+            let status = wire.varint("Status", "state", state);
+            let data = [wire.header(None), wire.bytes("Payload", "status", &status)].concat();
+            assert_eq!(invalid(&data), format!("unknown status state {}", state));
+        }
+
+        let status = [
+            wire.varint("Status", "state", wire.number("State", "STATE_ONLINE") as u64),
+            wire.bytes("Status", "config", b"{not json"),
+        ]
+        .concat();
+        let data = [wire.header(None), wire.bytes("Payload", "status", &status)].concat();
+        assert!(matches!(Encoding::Protobuf.decode(&data), Err(Error::Json(_))));
+
+        let response = wire.bytes("Response", "id_json", b"{7");
+        let data = [wire.header(None), wire.bytes("Payload", "response", &response)].concat();
+        assert!(matches!(Encoding::Protobuf.decode(&data), Err(Error::Json(_))));
+    }
+}
@@ -0,0 +1,244 @@
+// Home Assistant MQTT discovery: retained configs for the voltage sensors and
+// the alert binary sensors, reading the daemon topics, available while the
+// status is "online".
+
+use paho_mqtt as mqtt;
+use serde_json::{json, Value};
+
+use crate::mqtt::message;
+use crate::settings::{MessageSettings, Settings};
+use crate::topic::Identity;
+
+pub fn discovery(settings: &Settings, identity: &Identity, version: &str) -> Vec<mqtt::Message> {
+    let mqtt = &settings.mqtt;
+    let homeassistant = &settings.homeassistant;
+    let node_id: String = format!("volt_{}", identity.device_id)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    let device = json!({
+        "identifiers": [node_id],
+        "name": identity.render(&homeassistant.name),
+        "model": "ADC121C02x",
+        "sw_version": version,
+    });
+    let availability = json!([{
+        "topic": identity.topic(&mqtt.status_topic, "status"),
+        "value_template": "{{ value_json.value.state }}",
+        "payload_available": "online",
+        "payload_not_available": "offline",
+    }]);
+    let options = MessageSettings {
+        qos: homeassistant.qos,
+        retained: true,
+    };
+
+    let mut configs = Vec::new();
+    for (kind, name) in [
+        ("current_volt", "Voltage"),
+        ("lowest_volt", "Lowest voltage"),
+        ("highest_volt", "Highest voltage"),
+    ] {
+        // readings share a topic unless the template has {type}
+        let value_template = if mqtt.readings_topic.contains("{type}") {
+            "{{ value_json.value }}".to_string()
+        } else {
+            format!(
+                "{{{{ value_json.value if value_json.type == '{}' else this.state }}}}",
+                kind
+            )
+        };
+        let config = json!({
+            "name": name,
+            "unique_id": format!("{}_{}", node_id, kind),
+            "state_topic": identity.topic(&mqtt.readings_topic, kind),
+            "value_template": value_template,
+            "unit_of_measurement": "V",
+            "device_class": "voltage",
+            "state_class": "measurement",
+        });
+        configs.push(("sensor", kind.to_string(), config));
+    }
+    for (kind, name) in [("under", "Under voltage alert"), ("over", "Over voltage alert")] {
+        let config = json!({
+            "name": name,
+            "unique_id": format!("{}_alert_{}", node_id, kind),
+            "state_topic": identity.topic(&mqtt.events_topic, "alert_status_volt"),
+            "value_template": format!(
+                "{{% if value_json.value.kind == '{}' %}}{{{{ 'ON' if value_json.value.active else 'OFF' }}}}\
+                 {{% else %}}{{{{ 'ON' if this.state == 'on' else 'OFF' }}}}{{% endif %}}",
+                kind
+            ),
+            "device_class": "problem",
+        });
+        configs.push(("binary_sensor", format!("alert_{}", kind), config));
+    }
+
+    configs
+        .into_iter()
+        .map(|(component, object_id, mut config)| {
+            config["device"] = device.clone();
+            config["availability"] = availability.clone();
+            let topic = format!(
+                "{}/{}/{}/{}/config",
+                homeassistant.discovery_prefix, component, node_id, object_id
+            );
+            message(&options, &topic, Value::to_string(&config).into_bytes())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::monitor::{Limits, Values, VoltageEvent};
+    use crate::mqtt::{Publisher, Status};
+    use crate::payload::AlertKind;
+    use crate::sink::{MqttSink, Record, Sink};
+
+    fn identity() -> Identity {
+        Identity {
+            device_id: "dev.1".to_string(),
+            hostname: "host".to_string(),
+            channel: "0".to_string(),
+        }
+    }
+
+    fn configs(settings: &Settings) -> Vec<(String, Value)> {
+        discovery(settings, &identity(), "1.2.3")
+            .iter()
+            .map(|msg| {
+                assert!(msg.retained());
+                assert_eq!(msg.qos(), settings.homeassistant.qos);
+                (msg.topic().to_string(), serde_json::from_str(&msg.payload_str()).unwrap())
+            })
+            .collect()
+    }
+
+    //topics of the messages MqttSink publishes for these events
+    fn sink_topics(settings: &Settings, events: Vec<VoltageEvent>) -> Vec<String> {
+        let (publisher, mut rx) = Publisher::channel();
+        let mut sink = MqttSink::new(publisher, identity(), &settings.mqtt);
+        //current_volt waits for the timeout
+        thread::sleep(Duration::from_millis(2));
+        for event in events {
+            let limits = Limits {
+                under_range: 9.0,
+                over_range: 50.0,
+                hysteresis: 0.5,
+                timeout: 0,
+            };
+            sink.send(&Record { time_stamp: 1.0, event, limits }).unwrap();
+        }
+        drop(sink);
+        let mut topics = Vec::new();
+        while let Some(msg) = rx.blocking_recv() {
+            topics.push(msg.topic);
+        }
+        topics
+    }
+
+    fn readings() -> Vec<VoltageEvent> {
+        vec![
+            VoltageEvent::Reading(Values {
+                current: 12.0,
+                min: 11.0,
+                max: 13.0,
+                alert_under: false,
+                alert_over: false,
+            }),
+            VoltageEvent::NewLowest(11.0),
+            VoltageEvent::NewHighest(13.0),
+        ]
+    }
+
+    #[test]
+    fn config_topics() {
+        let configs = configs(&Settings::default());
+        let topics: Vec<&str> = configs.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/sensor/volt_dev_1/current_volt/config",
+                "homeassistant/sensor/volt_dev_1/lowest_volt/config",
+                "homeassistant/sensor/volt_dev_1/highest_volt/config",
+                "homeassistant/binary_sensor/volt_dev_1/alert_under/config",
+                "homeassistant/binary_sensor/volt_dev_1/alert_over/config",
+            ]
+        );
+        let ids: Vec<&str> = configs.iter().map(|(_, config)| config["unique_id"].as_str().unwrap()).collect();
+        assert_eq!(
+            ids,
+            [
+                "volt_dev_1_current_volt",
+                "volt_dev_1_lowest_volt",
+                "volt_dev_1_highest_volt",
+                "volt_dev_1_alert_under",
+                "volt_dev_1_alert_over",
+            ]
+        );
+        for (_, config) in &configs {
+            assert_eq!(config["device"]["identifiers"], json!(["volt_dev_1"]));
+            assert_eq!(config["device"]["name"], "volt dev.1");
+            assert_eq!(config["device"]["sw_version"], "1.2.3");
+        }
+    }
+
+    #[test]
+    fn state_topics_match_the_sink() {
+        for readings_topic in ["VOLT", "volt/{device_id}/{type}"] {
+            let mut settings = Settings::default();
+            settings.mqtt.readings_topic = readings_topic.to_string();
+            settings.mqtt.events_topic = "events/{hostname}".to_string();
+            let configs = configs(&settings);
+            let state_topics: Vec<&str> =
+                configs.iter().map(|(_, config)| config["state_topic"].as_str().unwrap()).collect();
+            let mut events = readings();
+            events.push(VoltageEvent::AlertRaised {
+                kind: AlertKind::Under,
+                value: 9.0,
+            });
+            events.push(VoltageEvent::AlertRaised {
+                kind: AlertKind::Over,
+                value: 50.0,
+            });
+            assert_eq!(state_topics, sink_topics(&settings, events));
+        }
+    }
+
+    #[test]
+    fn value_templates() {
+        let shared = configs(&Settings::default());
+        assert_eq!(
+            shared[1].1["value_template"],
+            "{{ value_json.value if value_json.type == 'lowest_volt' else this.state }}"
+        );
+        let mut settings = Settings::default();
+        settings.mqtt.readings_topic = "volt/{type}".to_string();
+        for (_, config) in &configs(&settings)[..3] {
+            assert_eq!(config["value_template"], "{{ value_json.value }}");
+        }
+        assert_eq!(
+            shared[3].1["value_template"],
+            "{% if value_json.value.kind == 'under' %}{{ 'ON' if value_json.value.active else 'OFF' }}\
+             {% else %}{{ 'ON' if this.state == 'on' else 'OFF' }}{% endif %}"
+        );
+    }
+
+    #[test]
+    fn availability() {
+        let mut settings = Settings::default();
+        settings.mqtt.status_topic = "status/{device_id}".to_string();
+        let status = Status::new(&settings.mqtt, &identity(), "1.2.3", tokio::sync::watch::channel(Value::Null).1);
+        for (_, config) in configs(&settings) {
+            let availability = &config["availability"][0];
+            assert_eq!(availability["topic"], status.online().topic());
+            assert_eq!(availability["topic"], "status/dev.1");
+            assert_eq!(availability["payload_available"], "online");
+            assert_eq!(availability["payload_not_available"], "offline");
+        }
+    }
+}
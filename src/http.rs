@@ -0,0 +1,311 @@
+// Local HTTP API (needs the "http" feature), JSON bodies:
+//
+// GET  /voltage     last reading: current, min, max, alerts and timeStamp
+// GET  /config      running configuration, with the current thresholds
+// GET  /health      200 while the readings are fresh, 503 otherwise
+// POST /thresholds  {"under_range": 9.0, "over_range": 50.0, "hysteresis": 0.5}, any subset
+
+use std::io;
+use std::sync::mpsc as std_mpsc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
+
+use crate::command::Command;
+
+// reading served by GET /voltage, from the values of the MQTT loop
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Voltage {
+    pub current: f32,
+    pub min: f32,
+    pub max: f32,
+    pub alert_under: bool,
+    pub alert_over: bool,
+    #[serde(rename = "timeStamp")]
+    pub time_stamp: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Thresholds {
+    pub under_range: Option<f32>,
+    pub over_range: Option<f32>,
+    pub hysteresis: Option<f32>,
+}
+
+// result of a command run by the daemon loop
+pub type Reply = std_mpsc::Sender<Result<(), String>>;
+
+// daemon state shared with the server thread
+pub struct Api {
+    pub readings: watch::Receiver<Option<Voltage>>,
+    pub config: watch::Receiver<serde_json::Value>,
+    pub commands: mpsc::UnboundedSender<(Command, Reply)>,
+    //GET /health fails when the last reading is older
+    pub stale_after: Duration,
+}
+
+#[cfg(not(feature = "http"))]
+pub fn spawn(_listen: &str, _api: Api) -> io::Result<()> {
+    Err(io::Error::other("HTTP API not built, enable the \"http\" feature"))
+}
+
+// serve the API from a thread, it runs until the process exits
+#[cfg(feature = "http")]
+pub fn spawn(listen: &str, api: Api) -> io::Result<()> {
+    let server = tiny_http::Server::http(listen).map_err(io::Error::other)?;
+    log::info!("HTTP API listening on {}", listen);
+    std::thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let (status, body) = match server::read_body(&mut request) {
+                Ok(body) => server::handle(&api, request.method(), request.url(), &body),
+                Err(err) => (400, server::error(&format!("invalid body: {}", err))),
+            };
+            let response = tiny_http::Response::from_string(body.to_string())
+                .with_status_code(status)
+                .with_header(server::json_header());
+            if let Err(err) = request.respond(response) {
+                log::warn!("HTTP response error: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(feature = "http")]
+mod server {
+    use std::io::{self, Read};
+    use std::sync::mpsc as std_mpsc;
+    use std::time::Duration;
+
+    use log::info;
+    use serde_json::{json, Value};
+    use tiny_http::{Header, Method, Request};
+
+    use super::{Api, Thresholds};
+    use crate::command::Command;
+    use crate::mqtt::timestamp;
+
+    const MAX_BODY: u64 = 4096;
+    const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn json_header() -> Header {
+        Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap()
+    }
+
+    pub fn error(message: &str) -> Value {
+        json!({ "error": message })
+    }
+
+    pub fn read_body(request: &mut Request) -> io::Result<String> {
+        let mut body = String::new();
+        request.as_reader().take(MAX_BODY).read_to_string(&mut body)?;
+        Ok(body)
+    }
+
+    //url with an optional query string, ignored
+    pub fn handle(api: &Api, method: &Method, url: &str, body: &str) -> (u16, Value) {
+        let path = url.split('?').next().unwrap_or_default();
+        match (method, path) {
+            (Method::Get, "/voltage") => match *api.readings.borrow() {
+                Some(voltage) => (200, json!(voltage)),
+                None => (503, error("no reading yet")),
+            },
+            (Method::Get, "/config") => (200, api.config.borrow().clone()),
+            (Method::Get, "/health") => health(api),
+            (Method::Post, "/thresholds") => thresholds(api, body),
+            (_, "/voltage") | (_, "/config") | (_, "/health") | (_, "/thresholds") => {
+                (405, error("method not allowed"))
+            }
+            _ => (404, error("not found")),
+        }
+    }
+
+    fn health(api: &Api) -> (u16, Value) {
+        let age = api.readings.borrow().map(|voltage| timestamp() - voltage.time_stamp);
+        match age {
+            Some(age) if age <= api.stale_after.as_secs_f64() => (200, json!({ "status": "ok", "age": age })),
+            _ => (503, json!({ "status": "stale", "age": age })),
+        }
+    }
+
+    fn thresholds(api: &Api, body: &str) -> (u16, Value) {
+        let thresholds: Thresholds = match serde_json::from_str(body) {
+            Ok(thresholds) => thresholds,
+            Err(err) => return (400, error(&format!("invalid request: {}", err))),
+        };
+        info!("HTTP thresholds: {:?}", thresholds);
+
+        if thresholds == Thresholds::default() {
+            return (400, error("under_range, over_range or hysteresis needed"));
+        }
+        //one command, nothing is changed if a value is rejected
+        let command = Command::SetThresholds {
+            under_range: thresholds.under_range,
+            over_range: thresholds.over_range,
+            hysteresis: thresholds.hysteresis,
+        };
+        let (reply, result) = std_mpsc::channel();
+        if api.commands.send((command, reply)).is_err() {
+            return (503, error("daemon stopping"));
+        }
+        match result.recv_timeout(COMMAND_TIMEOUT) {
+            Ok(Ok(())) => (200, api.config.borrow()["alert"].clone()),
+            Ok(Err(message)) => (400, error(&message)),
+            Err(_) => (503, error("no reply from the daemon")),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "http"))]
+mod tests {
+    use std::thread;
+
+    use serde_json::{json, Value};
+    use tiny_http::Method;
+
+    use super::*;
+    use crate::mqtt::timestamp;
+
+    struct Daemon {
+        api: Api,
+        readings: watch::Sender<Option<Voltage>>,
+        config: watch::Sender<Value>,
+        commands: mpsc::UnboundedReceiver<(Command, Reply)>,
+    }
+
+    fn daemon() -> Daemon {
+        let (readings_tx, readings) = watch::channel(None);
+        let (config_tx, config) = watch::channel(json!({"alert": {"under_range": 9.0}}));
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        let api = Api {
+            readings,
+            config,
+            commands: commands_tx,
+            stale_after: Duration::from_secs(3),
+        };
+        Daemon {
+            api,
+            readings: readings_tx,
+            config: config_tx,
+            commands,
+        }
+    }
+
+    fn voltage(time_stamp: f64) -> Voltage {
+        Voltage {
+            current: 12.0,
+            min: 11.0,
+            max: 13.0,
+            alert_under: false,
+            alert_over: true,
+            time_stamp,
+        }
+    }
+
+    #[test]
+    fn routing() {
+        let daemon = daemon();
+        let api = &daemon.api;
+        assert_eq!(server::handle(api, &Method::Get, "/voltage", "").0, 503);
+        daemon.readings.send(Some(voltage(1.5))).unwrap();
+        assert_eq!(
+            server::handle(api, &Method::Get, "/voltage?pretty", ""),
+            (
+                200,
+                json!({"current": 12.0, "min": 11.0, "max": 13.0, "alert_under": false, "alert_over": true, "timeStamp": 1.5})
+            )
+        );
+        assert_eq!(
+            server::handle(api, &Method::Get, "/config", ""),
+            (200, json!({"alert": {"under_range": 9.0}}))
+        );
+        assert_eq!(server::handle(api, &Method::Get, "/", "").0, 404);
+        assert_eq!(server::handle(api, &Method::Get, "/voltage/now", "").0, 404);
+        assert_eq!(server::handle(api, &Method::Post, "/other", "{}").0, 404);
+        assert_eq!(server::handle(api, &Method::Post, "/voltage", "").0, 405);
+        assert_eq!(server::handle(api, &Method::Delete, "/config", "").0, 405);
+        assert_eq!(server::handle(api, &Method::Post, "/health", "").0, 405);
+        assert_eq!(server::handle(api, &Method::Get, "/thresholds", "").0, 405);
+    }
+
+    #[test]
+    fn health() {
+        let daemon = daemon();
+        let api = &daemon.api;
+        let (status, body) = server::handle(api, &Method::Get, "/health", "");
+        assert_eq!((status, body), (503, json!({"status": "stale", "age": null})));
+        daemon.readings.send(Some(voltage(timestamp() - 1.0))).unwrap();
+        let (status, body) = server::handle(api, &Method::Get, "/health", "");
+        assert_eq!((status, &body["status"]), (200, &json!("ok")));
+        assert!(body["age"].as_f64().unwrap() >= 1.0);
+        daemon.readings.send(Some(voltage(timestamp() - 10.0))).unwrap();
+        let (status, body) = server::handle(api, &Method::Get, "/health", "");
+        assert_eq!((status, &body["status"]), (503, &json!("stale")));
+    }
+
+    #[test]
+    fn thresholds() {
+        let Daemon {
+            api,
+            readings: _readings,
+            config,
+            mut commands,
+        } = daemon();
+        //daemon loop: accepts the thresholds above 0
+        let loop_thread = thread::spawn(move || {
+            let mut received = Vec::new();
+            while let Some((command, reply)) = commands.blocking_recv() {
+                let result = match command {
+                    Command::SetThresholds {
+                        under_range: Some(under_range),
+                        ..
+                    } if under_range <= 0.0 => Err("under_range must be greater than 0".to_string()),
+                    Command::SetThresholds { under_range, .. } => {
+                        config.send(json!({"alert": {"under_range": under_range}})).unwrap();
+                        Ok(())
+                    }
+                    _ => Err("unexpected".to_string()),
+                };
+                received.push(command);
+                reply.send(result).unwrap();
+            }
+            received
+        });
+
+        let post = |body: &str| server::handle(&api, &Method::Post, "/thresholds", body);
+        assert_eq!(post(r#"{"under_range": 8.5}"#), (200, json!({"under_range": 8.5})));
+        let (status, body) = post(r#"{"under_range": -1.0, "hysteresis": 0.2}"#);
+        assert_eq!((status, body), (400, json!({"error": "under_range must be greater than 0"})));
+        //rejected before the daemon
+        assert_eq!(post("{}").0, 400);
+        assert_eq!(post("").0, 400);
+        assert_eq!(post(r#"{"under": 8.5}"#).0, 400);
+        assert_eq!(post(r#"{"under_range": "8.5"}"#).0, 400);
+        drop(api);
+        assert_eq!(
+            loop_thread.join().unwrap(),
+            [
+                Command::SetThresholds {
+                    under_range: Some(8.5),
+                    over_range: None,
+                    hysteresis: None,
+                },
+                Command::SetThresholds {
+                    under_range: Some(-1.0),
+                    over_range: None,
+                    hysteresis: Some(0.2),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn daemon_stopped() {
+        let Daemon { api, commands, .. } = daemon();
+        drop(commands);
+        let (status, _) = server::handle(&api, &Method::Post, "/thresholds", r#"{"hysteresis": 0.2}"#);
+        assert_eq!(status, 503);
+    }
+}
@@ -0,0 +1,145 @@
+// ADC ALERT line reported by an input device (gpio-keys) as key events. The
+// device is opened by path or looked up by name/phys, each mapped key raises
+// an under or over alert while its value is active.
+
+use std::io;
+
+use evdev::{Device, Key};
+
+use crate::payload::AlertKind;
+use crate::settings::{EvdevSettings, KeySettings};
+
+// highest key code (KEY_MAX)
+const KEY_MAX: u16 = 0x2ff;
+
+//key name (ex: KEY_PROG2) or code (ex: 148)
+pub fn parse_key(code: &str) -> Result<Key, String> {
+    if let Ok(key) = code.parse::<Key>() {
+        return Ok(key);
+    }
+    match code.parse::<u16>() {
+        Ok(number) if number <= KEY_MAX => Ok(Key::new(number)),
+        _ => Err(format!("{:?} is not a key name or code", code)),
+    }
+}
+
+// open the configured device, the first one matching name and phys when set
+pub fn open(settings: &EvdevSettings) -> io::Result<Device> {
+    if settings.name.is_none() && settings.phys.is_none() {
+        return Device::open(&settings.path)
+            .map_err(|err| io::Error::new(err.kind(), format!("input device {}: {}", settings.path, err)));
+    }
+    let matches = |wanted: &Option<String>, value: Option<&str>| match wanted {
+        Some(wanted) => value == Some(wanted.as_str()),
+        None => true,
+    };
+    evdev::enumerate()
+        .find(|device| matches(&settings.name, device.name()) && matches(&settings.phys, device.physical_path()))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no input device with name {:?} and phys {:?}", settings.name, settings.phys),
+            )
+        })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Mapping {
+    key: Key,
+    kind: AlertKind,
+    active_low: bool,
+}
+
+// key code and value polarity to alert
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyMap {
+    keys: Vec<Mapping>,
+}
+
+impl KeyMap {
+    pub fn new(keys: &[KeySettings]) -> Result<KeyMap, String> {
+        let keys = keys
+            .iter()
+            .map(|settings| {
+                Ok(Mapping {
+                    key: parse_key(&settings.code)?,
+                    kind: settings.alert,
+                    active_low: settings.active_low,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(KeyMap { keys })
+    }
+
+    //alert kind and state of a key event, None for unmapped keys
+    pub fn alert(&self, key: Key, value: i32) -> Option<(AlertKind, bool)> {
+        self.keys
+            .iter()
+            .find(|mapping| mapping.key == key)
+            .map(|mapping| (mapping.kind, (value != 0) != mapping.active_low))
+    }
+
+    //mapped keys the device doesn't report
+    pub fn unsupported(&self, device: &Device) -> Vec<Key> {
+        self.keys
+            .iter()
+            .map(|mapping| mapping.key)
+            .filter(|key| !device.supported_keys().is_some_and(|keys| keys.contains(*key)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: &str, alert: AlertKind, active_low: bool) -> KeySettings {
+        KeySettings {
+            code: code.to_string(),
+            alert,
+            active_low,
+        }
+    }
+
+    #[test]
+    fn key_names_and_codes() {
+        assert_eq!(parse_key("KEY_PROG2"), Ok(Key::KEY_PROG2));
+        assert_eq!(parse_key("149"), Ok(Key::KEY_PROG2));
+        assert_eq!(parse_key("767"), Ok(Key::new(KEY_MAX)));
+        for code in ["768", "-1", "KEY_NOPE", ""] {
+            assert!(parse_key(code).is_err(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn keys_and_polarity() {
+        let keymap = KeyMap::new(&[
+            key("KEY_PROG1", AlertKind::Under, false),
+            key("149", AlertKind::Over, true),
+        ])
+        .unwrap();
+        assert_eq!(keymap.alert(Key::KEY_PROG1, 1), Some((AlertKind::Under, true)));
+        assert_eq!(keymap.alert(Key::KEY_PROG1, 0), Some((AlertKind::Under, false)));
+        //autorepeat
+        assert_eq!(keymap.alert(Key::KEY_PROG1, 2), Some((AlertKind::Under, true)));
+        assert_eq!(keymap.alert(Key::KEY_PROG2, 0), Some((AlertKind::Over, true)));
+        assert_eq!(keymap.alert(Key::KEY_PROG2, 1), Some((AlertKind::Over, false)));
+        assert_eq!(keymap.alert(Key::KEY_PROG3, 1), None);
+    }
+
+    #[test]
+    fn first_mapping_wins() {
+        let keymap = KeyMap::new(&[
+            key("KEY_PROG1", AlertKind::Over, false),
+            key("KEY_PROG1", AlertKind::Under, false),
+        ])
+        .unwrap();
+        assert_eq!(keymap.alert(Key::KEY_PROG1, 1), Some((AlertKind::Over, true)));
+    }
+
+    #[test]
+    fn invalid_key() {
+        let err = KeyMap::new(&[key("KEY_PROG1", AlertKind::Under, false), key("KEY_NOPE", AlertKind::Over, false)]);
+        assert_eq!(err, Err("\"KEY_NOPE\" is not a key name or code".to_string()));
+    }
+}
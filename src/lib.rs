@@ -1,2 +1,20 @@
 pub mod adc;
-pub mod logs;
\ No newline at end of file
+pub mod alert;
+pub mod async_adc;
+pub mod bus;
+pub mod calibration;
+pub mod command;
+pub mod encoding;
+pub mod homeassistant;
+pub mod http;
+pub mod input;
+pub mod logs;
+pub mod monitor;
+pub mod mqtt;
+pub mod payload;
+pub mod proto;
+pub mod scale;
+pub mod settings;
+pub mod sink;
+pub mod sparkplug;
+pub mod topic;
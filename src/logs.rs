@@ -10,7 +10,8 @@ impl log::Log for SimpleLogger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            println!("{} - {}", record.level(), record.args());
+            //stdout is left to the stdout sink
+            eprintln!("{} - {}", record.level(), record.args());
         }
     }
 
@@ -1,36 +1,82 @@
 use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::thread;
 use std::time;
-use volt_i2c::adc::{FlagRegister, ADC};
+use volt_i2c::adc::{self, Config, CycleTime, ADC};
+use volt_i2c::alert::AlertSource;
+use volt_i2c::async_adc::AsyncAdc;
+use volt_i2c::calibration::Calibration;
+use volt_i2c::homeassistant;
+use volt_i2c::http::{self, Voltage};
 use volt_i2c::logs;
+use volt_i2c::monitor::{self, Limits, Monitor, VoltageEvent};
+use volt_i2c::scale::Scale;
+use volt_i2c::settings::{self, AlertInput, Settings};
+use volt_i2c::sink::{self, FanOut, MqttSink, Record};
+use volt_i2c::sparkplug::{self, MetricsSink, Node};
+use volt_i2c::topic::Identity;
+use std::fmt::Display;
+use std::str::FromStr;
 // use std::sync::{Arc};
 // use std::sync::atomic::{AtomicBool, Ordering};
-use clap::{self, App, Arg};
-use paho_mqtt as mqtt;
+use clap::{self, App, Arg, ArgMatches, SubCommand};
+use volt_i2c::command::Request;
+use volt_i2c::mqtt::{self as broker, Publisher, Status, Subscription};
+use volt_i2c::payload::{self, Body, Payload, Response};
 use std::process;
-use tokio;
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
 use tokio::time::{Duration,sleep};
 use log::{debug, error, info, warn};
-use evdev::{Device, InputEventKind, Key};
 
-const APPNAME: &'static str = "volt";
+const APPNAME: &str = "volt";
 
 const VERSION: Option<&str> = option_env!("CARGO_PKG_VERSION");
 
+// MQTT publishing mode
+enum Output {
+    Daemon(Publisher, JoinHandle<()>),
+    Sparkplug(JoinHandle<()>),
+}
+
+fn setup_adc(
+    dev: &mut ADC,
+    config: &Config,
+    scale: &Scale,
+    over_range: f32,
+    under_range: f32,
+    hys_value: f32,
+) -> adc::Result<()> {
+    dev.set_scale(scale.clone());
+    dev.set_config(config, true)?;
+    dev.set_alert_over_range(over_range)?;
+    dev.set_alert_under_range(under_range)?;
+    dev.set_alert_hysteresis(hys_value)?;
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = App::new("volt")
         .version(VERSION.unwrap_or("unknown"))
         .author("soporte <soporte@nebulae.com.co>")
         .about("ADC sensor")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("config")
+                .help("Set settings file path, options in the command line override it [default: /etc/volt.toml]")
+                .global(true)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("alert-under-range")
                 .short("u")
                 .long("underRange")
                 .value_name("under_range")
-                .help("Set alert under range value")
-                .default_value("9.5")
+                .help("Set alert under range value [default: 9.5]")
                 .takes_value(true),
         )
         .arg(
@@ -38,8 +84,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .short("o")
                 .long("overRange")
                 .value_name("over_range")
-                .help("Set alert over range value")
-                .default_value("50.0")
+                .help("Set alert over range value [default: 50.0]")
                 .takes_value(true),
         )
         .arg(
@@ -47,8 +92,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .short("i")
                 .long("hysValue")
                 .value_name("hys_value")
-                .help("Set hysteresis value")
-                .default_value("1.0")
+                .help("Set hysteresis value [default: 1.0]")
                 .takes_value(true),
         )
         .arg(
@@ -56,10 +100,104 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .short("t")
                 .long("timeout")
                 .value_name("timeout")
-                .help("Set timeout value in secs")
-                .default_value("60")
+                .help("Set timeout value in secs [default: 60]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bus")
+                .short("b")
+                .long("bus")
+                .value_name("bus")
+                .help("Set i2c bus device path [default: /dev/i2c-2]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("address")
+                .short("a")
+                .long("address")
+                .value_name("address")
+                .help("Set ADC 7-bit slave address (0x50 - 0x5A) [default: 0x54]")
+                .validator(|v| parse_address(&v).map(|_| ()))
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("bus-timeout")
+                .long("busTimeout")
+                .value_name("bus_timeout")
+                .help("Set timeout for each i2c operation in millis [default: 1000]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("vref")
+                .long("vref")
+                .value_name("vref")
+                .help("Set ADC reference voltage [default: 4.096]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("divider")
+                .long("divider")
+                .value_name("divider")
+                .help("Set input divider ratio (Vin / Vadc) [default: 16.0]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gain")
+                .long("gain")
+                .value_name("gain")
+                .help("Set calibration gain (overrides calibration file)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("offset")
+                .long("offset")
+                .value_name("offset")
+                .help("Set calibration offset in volts (overrides calibration file)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("calibration")
+                .short("c")
+                .long("calibration")
+                .value_name("calibration")
+                .help("Set calibration file path [default: /etc/volt/calibration.toml]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("broker")
+                .long("broker")
+                .value_name("broker")
+                .help("Set MQTT broker URI, repeat for fallback brokers [default: tcp://localhost:1883]")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("client-id")
+                .long("clientId")
+                .value_name("client_id")
+                .help("Set MQTT client id")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("username")
+                .long("username")
+                .value_name("username")
+                .help("Set MQTT username")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("password")
+                .long("password")
+                .value_name("password")
+                .help("Set MQTT password")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-evdev")
+                .long("no-evdev")
+                .help("Poll the ADC alert registers instead of reading the alert input device")
+        )
         .arg(
             Arg::with_name("logStd")
                 .short("l")
@@ -78,6 +216,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 .long("version")
                 .help("show version"),
         )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Settings file tools")
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Validate the settings file and print the resulting settings"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("calibrate")
+                .about("Calibrate gain and offset with one or two known reference voltages")
+                .arg(
+                    Arg::with_name("reference")
+                        .value_name("reference")
+                        .help("Reference voltages applied to the input")
+                        .required(true)
+                        .min_values(1)
+                        .max_values(2),
+                )
+                .arg(
+                    Arg::with_name("samples")
+                        .short("n")
+                        .long("samples")
+                        .value_name("samples")
+                        .help("Set readings averaged for each reference")
+                        .default_value("32")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("schema")
+                .about("Print the JSON schema of the published messages"),
+        )
         .get_matches();
 
     let logstd = args.is_present("logStd");
@@ -88,19 +259,63 @@ async fn main() -> Result<(), Box<dyn Error>> {
         process::exit(1);
     }
 
-   
+    if let Some(config) = args.subcommand_matches("config") {
+        if config.subcommand_matches("check").is_some() {
+            return config_check(&args);
+        }
+    }
+    if args.subcommand_matches("schema").is_some() {
+        println!("{}", payload::json_schema());
+        return Ok(());
+    }
+
+    let settings = load_settings(&args)?;
 
     logs::init_std_log(logstd, debug, APPNAME)?;
     info!(r#"runnin "{}", version "{}""#, APPNAME, VERSION.unwrap_or("unknown"));
 
-    let timeout: u64 = clap::value_t!(args.value_of("timeout"), u64).unwrap_or(30);
-    let over_range: f32 = clap::value_t!(args.value_of("alert-over-range"), f32).unwrap_or(50.0);
-    let under_range: f32 = clap::value_t!(args.value_of("alert-under-range"), f32).unwrap_or(9.5);
-    let hys_value: f32 = clap::value_t!(args.value_of("hysteresis-value"), f32).unwrap_or(1.0);
+    let identity = Identity::resolve(&settings.device)?;
+    info!("device id: {}, hostname: {}, channel: {}", identity.device_id, identity.hostname, identity.channel);
+
+    let over_range = settings.alert.over_range;
+    let under_range = settings.alert.under_range;
+    let hys_value = settings.alert.hysteresis;
+    let bus = settings.adc.bus.clone();
+    let address = settings.adc.address;
+    let calibration_path = settings.adc.calibration.as_str();
+    let nominal = settings.scale()?;
+
+    let config = Config {
+        cycle_time: CycleTime::Tx32,
+        alert_flag_enable: true,
+        alert_pin_enable: true,
+        // alert_hold: true,
+        ..Config::default()
+    };
+
+    if let Some(sub) = args.subcommand_matches("calibrate") {
+        let mut dev = ADC::open(&bus, address)?;
+        dev.set_config(&config, true)?;
+        return calibrate(dev, nominal, calibration_path, sub);
+    }
 
-    println!("alert over range: {}", over_range);
-    println!("alert under range: {}", under_range);
-    println!("hysteresis value: {}", hys_value);
+    let mut calibration = if Path::new(calibration_path).exists() {
+        let calibration = Calibration::load(calibration_path)
+            .map_err(|err| format!("calibration file {}: {}", calibration_path, err))?;
+        info!("calibration loaded from {}: {:?}", calibration_path, calibration);
+        calibration
+    } else {
+        Calibration::default()
+    };
+    calibration.gain = settings.adc.gain.unwrap_or(calibration.gain);
+    calibration.offset = settings.adc.offset.unwrap_or(calibration.offset);
+    let scale = calibration.apply(nominal);
+
+    info!("alert over range: {}", over_range);
+    info!("alert under range: {}", under_range);
+    info!("hysteresis value: {}", hys_value);
+    info!("i2c bus: {}, address: {:#X}", bus, address);
+    info!("scale: {:?}, volts/LSB: {}", scale, scale.lsb() * scale.gain);
 
     let mut term = signal(SignalKind::terminate())?;
     let mut inte = signal(SignalKind::interrupt())?;
@@ -108,366 +323,407 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // const LOWEST_VALUE: f32 = 9.5;
     // const HIHGEST_VALUE: f32 = 50.0;
 
-    #[derive(Debug)]
-    struct Values {
-        current: f32,
-        min: f32,
-        max: f32,
-        alert_under: bool,
-        alert_over: bool,
-    }
-
     // let term = Arc::new(AtomicBool::new(false));
     // signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&term))?;
 
-    let flags = FlagRegister::AlertFlagEnable as u8
-        | FlagRegister::AlertPINEnable as u8
-        | FlagRegister::Tx32 as u8;
-        // | FlagRegister::AlertHold as u8;
-
     sleep(Duration::from_millis(100)).await;
-    let mut dev = ADC::new()?;
+    let mut dev = ADC::open(&bus, address)?;
 
     let result = dev.read_register_byte(0x00)?;
-    println!("register: {}", result);
+    debug!("register: {}", result);
 
-    dev.set_conf_register(flags)?;
-    dev.set_alert_over_range(over_range)?;
-    dev.set_alert_under_range(under_range)?;
-    dev.set_alert_hysteresis(hys_value)?;
+    setup_adc(&mut dev, &config, &scale, over_range, under_range, hys_value)?;
 
     let (result, alert) = dev.read_value()?;
-    println!("volt now: {}", result);
+    info!("volt now: {}", result);
     if alert {
         let (over, under) = dev.read_alert()?;
-        println!("alert?: over: {}, under {}", over, under);
+        info!("alert?: over: {}, under {}", over, under);
     }
     let min = dev.read_min_value()?;
-    println!("min: {}", min);
-    let mut min_old = min;
-    dev.write_min_value(50.0)?;
+    info!("min: {}", min);
+    let min_old = min;
+    dev.write_min_value(monitor::RESET_MIN)?;
     let max = dev.read_max_value()?;
-    println!("max: {}", max);
-    let mut max_old = max;
-    dev.write_max_value(1.0)?;
+    info!("max: {}", max);
+    let max_old = max;
+    dev.write_max_value(monitor::RESET_MAX)?;
 
     let register = dev.read_register_word(0x04)?;
-    println!("register 0x04: {:#X}", register);
+    debug!("register 0x04: {:#X}", register);
     let register = dev.read_register_word(0x03)?;
-    println!("register 0x03: {:#X}", register);
+    debug!("register 0x03: {:#X}", register);
     let register = dev.read_register_word(0x05)?;
-    println!("register 0x05: {:#X}", register);
+    debug!("register 0x05: {:#X}", register);
     let register = dev.read_register_byte(0x01)?;
-    println!("register 0x01: {:#X}", register);
+    debug!("register 0x01: {:#X}", register);
     let register = dev.read_register_byte(0x02)?;
-    println!("register 0x02: {:#X}", register);
+    debug!("register 0x02: {:#X}", register);
+    debug!("config: {:?}", dev.read_config()?);
+
+    let adc = AsyncAdc::spawn(dev, Duration::from_millis(settings.adc.bus_timeout));
+
+    let limits = Limits {
+        under_range,
+        over_range,
+        hysteresis: hys_value,
+        timeout: settings.publish.timeout,
+    };
+    let (config_tx, config_rx) = watch::channel(config_json(&settings, &identity, &limits));
 
     // Create a client & define connect options
-    let cli = mqtt::AsyncClient::new("tcp://localhost:1883").unwrap_or_else(|err| {
+    let cli = broker::create_client(&settings.mqtt, &identity).unwrap_or_else(|err| {
         error!("Error creating the client: {}", err);
         process::exit(1);
     });
 
-    let conn_opts = mqtt::ConnectOptions::new();
+    // MQTT commands, disabled with an empty command_topic
+    let (command_tx, mut commands) = mpsc::unbounded_channel();
+
+    // Monitor events to MQTT (or Sparkplug) and the [[sinks]]
+    let mut sinks = FanOut::new();
+
+    // Sparkplug B replaces the daemon topics, status and discovery
+    let output = if settings.sparkplug.enabled {
+        drop(command_tx);
+        let (metrics_tx, metrics_rx) = watch::channel(None);
+        let node = Node::new(&settings.sparkplug, &identity, VERSION.unwrap_or("unknown"));
+        info!("Sparkplug B node: {}", node.node_topic("NBIRTH"));
+        let task = sparkplug::spawn(cli, &settings.mqtt, node, metrics_rx).unwrap_or_else(|err| {
+            error!("Invalid MQTT connect options: {}", err);
+            process::exit(1);
+        });
+        sinks.add(Box::new(MetricsSink::new(metrics_tx)), &settings.mqtt.events)?;
+        Output::Sparkplug(task)
+    } else {
+        // Retained online/offline status with the running configuration
+        let status = Status::new(
+            &settings.mqtt,
+            &identity,
+            VERSION.unwrap_or("unknown"),
+            config_rx.clone(),
+        );
+        let conn_opts = broker::connect_options(&settings.mqtt, Some(status.offline(false))).unwrap_or_else(|err| {
+            error!("Invalid MQTT connect options: {}", err);
+            process::exit(1);
+        });
+
+        let subscription = if settings.mqtt.command_topic.is_empty() {
+            None
+        } else {
+            Some(Subscription {
+                topic: identity.render(&settings.mqtt.command_topic),
+                qos: settings.mqtt.command_qos,
+                tx: command_tx,
+            })
+        };
 
-    // Connect and wait for it to complete or fail
-    if let Err(e) = cli.connect(conn_opts).wait() {
-        error!("Unable to connect: {:?}", e);
-        process::exit(1);
-    }
+        // Connect in background, messages are buffered until the broker is reachable
+        let (publisher, publisher_task) = Publisher::spawn(cli, conn_opts, &settings.mqtt, status, subscription);
 
-    let (tx, mut rx) = mpsc::channel(32);
-    let mut tick = tokio::time::interval(Duration::from_secs(3));
-    
+        // Retained Home Assistant discovery configs, sent after the online status
+        if settings.homeassistant.enabled {
+            for msg in homeassistant::discovery(&settings, &identity, VERSION.unwrap_or("unknown")) {
+                publisher.publish(msg);
+            }
+        }
+        let mqtt_sink = MqttSink::new(publisher.clone(), identity.clone(), &settings.mqtt);
+        sinks.add(Box::new(mqtt_sink), &settings.mqtt.events)?;
+        Output::Daemon(publisher, publisher_task)
+    };
+    for settings in &settings.sinks {
+        sinks.add(sink::open(settings)?, &settings.events)?;
+    }
 
-    //evedev
-    let filename: &str = args.value_of("filepath").unwrap_or("/dev/input/event0");
-    let device = Device::open(filename)?;
-    let evdev_with_keypro2 = device.supported_keys().map_or(false, |keys| {
-        log::info!("key: {:?}", keys);
-        !keys.contains(Key::KEY_PROG2)
+    // ALERT line input, without it the alerts are read from the ADC registers
+    // at the faster poll_tick
+    let alerts = AlertSource::open(&settings)?;
+    let period = Duration::from_secs_f32(if alerts.is_polled() {
+        info!("alert source: {}, poll every {}s", alerts, settings.publish.poll_tick);
+        settings.publish.poll_tick
+    } else {
+        info!("alert source: {}", alerts);
+        settings.publish.tick
     });
 
-    let mut events = device.into_event_stream()?;
-
-    tokio::spawn(async move {
-        let mut min_old = 0.0;
-        let mut max_old = 0.0;
-        let mut current_old = 0.0;
-        loop {
-            tokio::select! {
-
-               event = events.next_event() => {
-                    match event {
-                        Ok(ev) => {
-                            let kind = ev.kind();
-                            if let InputEventKind::Key(key) = kind {
-                                match key {
-                                    Key::KEY_PROG2 => {
-                                        let min = dev.read_min_value().unwrap_or_else(|error| {
-                                            warn!("ADC read_min_value error: {}", error);
-                                            -1.0
-                                        });
-                                        let (current, _) = dev.read_value().unwrap_or_else(|error| {
-                                            warn!("ADC read_value error: {}", error);
-                                            (-1.0, false)
-                                        });                                        
-                                        warn!("ADC alert: {}, volt: {}, min: {}", ev.value(), current, min);
-                                        let value = Values{
-                                            current: current,
-                                            min: if min > -1.0 {
-                                                min
-                                            } else if current > -1.0 {
-                                                current
-                                            } else {
-                                                -1.0
-                                            },
-                                            max: max_old,
-                                            alert_over: false,
-                                            alert_under: ev.value() != 0,
-                                        };
-                                        if let Err(err) = tx.send(value).await {
-                                            error!("event err: {}", err);
-                                            tx.closed().await;
-                                            return ();
-                                        }                                    
-                                    }
-                                    Key::KEY_PROG1 => {
-                                        if !evdev_with_keypro2 {
-                                            let min = dev.read_min_value().unwrap_or_else(|error| {
-                                                warn!("ADC read_min_value error: {}", error);
-                                                -1.0
-                                            });
-                                            let (current, _) = dev.read_value().unwrap_or_else(|error| {
-                                                warn!("ADC read_value error: {}", error);
-                                                (-1.0, false)
-                                            });
-                                            
-                                            warn!("ADC alert: {}, volt: {}, min: {}", ev.value(), current, min);
-                                            let value = Values{
-                                                current: current,
-                                                min: if min > -1.0 {
-                                                    min
-                                                } else if current > -1.0 {
-                                                    current
-                                                } else {
-                                                    -1.0
-                                                },
-                                                max: max_old,
-                                                alert_over: false,
-                                                alert_under: ev.value() != 0,
-                                            };
-                                            if let Err(err) = tx.send(value).await {
-                                                error!("event err: {}", err);
-                                                tx.closed().await;
-                                                return ();
-                                            }
-                                        }             
-                                    }
-                                    _ => {}
-                                }
+    // Local HTTP API, fed from the values of the loop below
+    let (readings_tx, readings_rx) = watch::channel(None);
+    let (http_tx, mut http_commands) = mpsc::unbounded_channel();
+    if settings.http.enabled {
+        let api = http::Api {
+            readings: readings_rx,
+            config: config_rx,
+            commands: http_tx,
+            //three missed readings of the monitor loop
+            stale_after: period * 3,
+        };
+        http::spawn(&settings.http.listen, api).map_err(|err| format!("HTTP API on {}: {}", settings.http.listen, err))?;
+    } else {
+        drop(http_tx);
+    }
+
+    // Monitoring loop, a missing ADC is opened and set up again
+    let (control, mut events) = Monitor::new(adc, alerts, limits, period)
+        .with_extremes(min_old, max_old)
+        .with_reopen(move |limits| {
+            let mut dev = ADC::open(&bus, address)?;
+            setup_adc(
+                &mut dev,
+                &config,
+                &scale,
+                limits.over_range,
+                limits.under_range,
+                limits.hysteresis,
+            )?;
+            Ok(dev)
+        })
+        .spawn();
+
+    // ADC error that stopped the monitor, exit status 1
+    let mut fatal = None;
+    loop {
+        let event = tokio::select! {
+            event = events.next() => match event {
+                Some(event) => event,
+                None => {
+                    fatal = events.error();
+                    break;
+                }
+            },
+            Some(msg) = commands.recv() => {
+                let response = match Request::from_json(msg.payload()) {
+                    Ok(request) => {
+                        info!("MQTT command on {}: {:?}", msg.topic(), request);
+                        let name = request.command.name();
+                        let result = control.execute(request.command).await;
+                        match &result {
+                            Ok(_) => {
+                                let _ = config_tx.send(config_json(&settings, &identity, &control.limits()));
                             }
+                            Err(err) => warn!("MQTT command {} error: {}", name, err),
                         }
-                        Err(err) => {
-                            warn!("event err: {}", err);
-                        }
-                    }                   
-                },   
-                                
-                _ = term.recv() => {
-                    let _ = tx.closed();
-                    error!("Received SIGTERM kill signal. Exiting...");
-                    return ()
-                },
-                _ = inte.recv() => {
-                    let _ = tx.closed();
-                    error!("Received SIGINT kill signal. Exiting...");
-                    return ()
-                },
-                _ = tick.tick() => {
-                    let (current, alert) = dev.read_value().unwrap_or_else(|error| {
-                        warn!("ADC read_value error: {}", error);
-                        (current_old, false)
-                    });
-                    let mut alert_under = false;
-                    let mut alert_over = false;
-                    if alert {
-                        let (alert_over_t, alert_under_t) = dev.read_alert().unwrap_or_else(|error| {
-                            warn!("ADC read_value error: {}", error);
-                            (false, false)
-                        });
-                        alert_under = alert_under_t;
-                        alert_over = alert_over_t;
-                    }
-                    let min = dev.read_min_value().unwrap_or_else(|error| {
-                        warn!("ADC read_min_value error: {}", error);
-                        min_old
-                    });
-
-                    let max = dev.read_max_value().unwrap_or_else(|error| {
-                        warn!("ADC read_max_value error: {}", error);
-                        max_old
-                    });
-
-                    let value = Values{
-                        current: current,
-                        min: min,
-                        max: max,
-                        alert_over: alert_over,
-                        alert_under: alert_under,
-                    };
-                    if let Err(error) = tx.send(value).await {
-                        error!("sending error: {}", error);
-                        return ()
-                    }
-
-                    // // if alter_hold is enables comment out
-                    // if alert {
-                    //     dev.clear_alerts().unwrap_or_else(|error| {
-                    //         warn!("ADC read_max_value error: {}", error);
-                    //         ()
-                    //     });
-                    // }
-                    if min < under_range && current > under_range {
-                        dev.write_min_value(50.0).unwrap_or_else(|error| {
-                            warn!("ADC write_min_value error: {}", error);
-                            ()
-                        });
+                        Response::new(request.id, Some(name), result)
                     }
-                    if max > over_range && current < over_range {
-                        dev.write_max_value(1.0).unwrap_or_else(|error| {
-                            warn!("ADC write_max_value error: {}", error);
-                            ()
-                        });
+                    Err(invalid) => {
+                        warn!("MQTT command on {}: {}", msg.topic(), invalid.error);
+                        Response::new(invalid.id, None, Err(invalid.error))
                     }
-
-                    min_old = min;
-                    max_old = max;
-                    current_old = current;
-                },
-            }
-
-            // thread::sleep(time::Duration::from_secs(1));
-        }
-    });
-
-    let mut alert_over = false;
-    let mut alert_under = false;
-    let mut old_time = time::SystemTime::now();
-    while let Some(received) = rx.recv().await {
-        let nsec = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
-            Ok(n) => n.as_secs_f64(),
-            Err(_) => {
-                warn!("SystemTime before UNIX EPOCH!");
-                0.0
+                };
+                let payload = Payload::new(broker::timestamp(), Body::Response(response));
+                let msg = broker::message(
+                    &settings.mqtt.response,
+                    &identity.topic(&settings.mqtt.response_topic, payload.body.name()),
+                    settings.mqtt.response_encoding.encode(&payload),
+                );
+                if let Output::Daemon(publisher, _) = &output {
+                    publisher.publish(msg);
+                }
+                continue;
             }
-        };
-
-        let elapse = old_time.elapsed();
-        match elapse {
-            Ok(value) => {
-                if value > time::Duration::from_secs(timeout) && received.current > 0.0 {
-                    debug!("1970-01-01 00:00:00 UTC was {} seconds ago!", nsec);
-                    old_time = time::SystemTime::now();
-                    debug!("Publishing a message on the 'EVENTS/volt' topic");
-                    debug!("Got: {:?}", received);
-                    println!("current_volt: {}", received.current);
-                    //let msg = mqtt::Message::new("test", "Hello world!", 0);
-                    let msg = mqtt::Message::new(
-                        "VOLT",
-                        format!(
-                            r#"{{"timeStamp": {}, "value": {}, "type": "current_volt"}}"#,
-                            nsec, received.current
-                        ),
-                        0,
-                    );
-                    let tok = cli.publish(msg);
-                    if let Err(e) = tok.wait() {
-                        error!("Error sending message: {:?}", e);
+            Some((command, reply)) = http_commands.recv() => {
+                info!("HTTP command: {:?}", command);
+                let name = command.name();
+                let result = control.execute(command).await.map(|_| ());
+                match &result {
+                    Ok(()) => {
+                        let _ = config_tx.send(config_json(&settings, &identity, &control.limits()));
                     }
+                    Err(err) => warn!("HTTP command {} error: {}", name, err),
                 }
+                let _ = reply.send(result);
+                continue;
             }
-            Err(_) => {}
-        };   
-
-        if received.alert_under != alert_under {
-            if received.min >= 0.0 {
-                println!("alert_volt min: {}", received.min);
-                warn!("alert_volt min -> {}", received.min);
-            }
-            let msg = mqtt::Message::new(
-                "EVENTS/volt",
-                format!(
-                    r#"{{"timeStamp": {}, "value": {{ "value": {}, "active": {} }}, "type": "alert_status_volt"}}"#,
-                    nsec, if received.alert_under { received.min } else { received.current }, received.alert_under,
-                ),
-                0,
-            );
-            let tok = cli.publish(msg);
-            if let Err(e) = tok.wait() {
-                error!("Error sending message: {:?}", e);
+            _ = term.recv() => {
+                error!("Received SIGTERM kill signal. Exiting...");
+                break;
             }
-        }
-        alert_under = received.alert_under;
-
-        if received.alert_over != alert_over {
-            println!("alert_volt max: {}", received.max);
-            warn!("alert_volt max-> {}", received.max);
-           
-            let msg = mqtt::Message::new(
-                "EVENTS/volt",
-                format!(
-                    r#"{{"timeStamp": {}, "value": {{ "value": {}, "active": {} }}, "type": "alert_status_volt"}}"#,
-                    nsec, if received.alert_over { received.max } else { received.current }, received.alert_over,
-                ),
-                0,
-            );
-            let tok = cli.publish(msg);
-            if let Err(e) = tok.wait() {
-                error!("Error sending message: {:?}", e);
+            _ = inte.recv() => {
+                error!("Received SIGINT kill signal. Exiting...");
+                break;
             }
+        };
+        let record = Record {
+            time_stamp: broker::timestamp(),
+            event,
+            limits: control.limits(),
+        };
+        if let VoltageEvent::Reading(values) = event {
+            let _ = readings_tx.send(Some(Voltage {
+                current: values.current,
+                min: values.min,
+                max: values.max,
+                alert_under: values.alert_under,
+                alert_over: values.alert_over,
+                time_stamp: record.time_stamp,
+            }));
         }
-        alert_over = received.alert_over;
-
-        if received.min > 0.0 && min_old > received.min {
-            warn!("lowest_volt -> {}", received.min);
-            min_old = received.min - hys_value;            
-            let msg = mqtt::Message::new(
-                "VOLT",
-                format!(
-                    r#"{{"timeStamp": {}, "value": {}, "type": "lowest_volt"}}"#,
-                    nsec, received.min
-                ),
-                0,
-            );
-            let tok = cli.publish(msg);
-            if let Err(e) = tok.wait() {
-                error!("Error sending message: {:?}", e);
-            }            
+        sinks.send(&record);
+    }
+
+    match &fatal {
+        Some(err) => error!("ADC error: {}. Exiting...", err),
+        None => info!("Received kill signal. Exiting..."),
+    }
+
+    // Flush buffered messages (or publish NDEATH) and disconnect from the broker
+    sinks.close();
+    match output {
+        Output::Daemon(publisher, task) => {
+            drop(publisher);
+            task.await?;
         }
-        if max_old  < received.max {
-            warn!("highest_volt -> {}", received.max);
-            max_old = received.max + hys_value;
-        
-            let msg = mqtt::Message::new(
-                "VOLT",
-                format!(
-                    r#"{{"timeStamp": {}, "value": {}, "type": "highest_volt"}}"#,
-                    nsec, received.max
-                ),
-                0,
-            );
-            let tok = cli.publish(msg);
-            if let Err(e) = tok.wait() {
-                error!("Error sending message: {:?}", e);
-            }                  
+        Output::Sparkplug(task) => task.await?,
+    }
+    match fatal {
+        Some(err) => Err(err.into()),
+        None => Ok(()),
+    }
+}
+
+//running configuration (status and GET /config), credentials are left out
+fn config_json(settings: &Settings, identity: &Identity, limits: &Limits) -> serde_json::Value {
+    serde_json::json!({
+        "device": { "id": identity.device_id, "hostname": identity.hostname, "channel": identity.channel },
+        "adc": { "bus": settings.adc.bus, "address": settings.adc.address },
+        "alert": {
+            "under_range": limits.under_range,
+            "over_range": limits.over_range,
+            "hysteresis": limits.hysteresis,
+        },
+        "publish": {
+            "timeout": limits.timeout,
+            "tick": settings.publish.tick,
+            "poll_tick": settings.publish.poll_tick,
+        },
+        "encoding": {
+            "readings": settings.mqtt.readings_encoding,
+            "events": settings.mqtt.events_encoding,
+        },
+    })
+}
+
+//address in hex (0x54) or decimal (84)
+fn parse_address(value: &str) -> Result<u16, String> {
+    let address = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse::<u16>(),
+    }
+    .map_err(|err| format!("invalid address {:?}: {}", value, err))?;
+    if !adc::SLAVE_ADDRS.contains(&address) {
+        return Err(format!("address {:#X} out of ADC121C02x range", address));
+    }
+    Ok(address)
+}
+
+//average readings for each reference voltage and save the computed gain/offset
+fn calibrate(mut dev: ADC, nominal: Scale, path: &str, args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let samples: usize = clap::value_t!(args.value_of("samples"), usize)?;
+    if samples == 0 {
+        return Err("samples must be greater than 0".into());
+    }
+    let references = clap::values_t!(args.values_of("reference"), f32)?;
+
+    dev.set_scale(nominal);
+    let mut points = Vec::new();
+    for reference in references {
+        println!("apply {} V to the input and press Enter", reference);
+        io::stdin().read_line(&mut String::new())?;
+        let mut sum = 0.0;
+        for _ in 0..samples {
+            let (value, _) = dev.read_value()?;
+            sum += value;
+            thread::sleep(time::Duration::from_millis(50));
         }
+        let measured = sum / samples as f32;
+        println!("reference: {} V, measured: {} V", reference, measured);
+        points.push((measured, reference));
     }
 
-    println!("Received kill signal. Exiting...");
+    let calibration = Calibration::from_points(&points)?;
+    calibration.save(path)?;
+    println!("calibration: {:?}, saved in {}", calibration, path);
+    Ok(())
+}
 
-    // Disconnect from the broker
-    let tok = cli.disconnect(None);
-    tok.wait()?;
+//settings file overridden by the command line options
+fn load_settings(args: &ArgMatches) -> Result<Settings, Box<dyn Error>> {
+    let path = args.value_of("config").unwrap_or(settings::SETTINGS_PATH);
+    let mut settings = if args.occurrences_of("config") > 0 || Path::new(path).exists() {
+        Settings::load(path)?
+    } else {
+        Settings::default()
+    };
+
+    override_value(args, "alert-under-range", &mut settings.alert.under_range)?;
+    override_value(args, "alert-over-range", &mut settings.alert.over_range)?;
+    override_value(args, "hysteresis-value", &mut settings.alert.hysteresis)?;
+    override_value(args, "timeout", &mut settings.publish.timeout)?;
+    override_value(args, "bus", &mut settings.adc.bus)?;
+    if let Some(address) = args.value_of("address") {
+        settings.adc.address = parse_address(address)?;
+    }
+    override_value(args, "bus-timeout", &mut settings.adc.bus_timeout)?;
+    override_value(args, "vref", &mut settings.adc.vref)?;
+    override_value(args, "divider", &mut settings.adc.divider)?;
+    override_value(args, "calibration", &mut settings.adc.calibration)?;
+    if let Some(brokers) = args.values_of("broker") {
+        settings.mqtt.brokers = brokers.map(String::from).collect();
+    }
+    override_value(args, "client-id", &mut settings.mqtt.client_id)?;
+    if let Some(username) = args.value_of("username") {
+        settings.mqtt.username = Some(username.to_string());
+    }
+    if let Some(password) = args.value_of("password") {
+        settings.mqtt.password = Some(password.to_string());
+    }
+    if args.is_present("no-evdev") && settings.alert.source == AlertInput::Evdev {
+        settings.alert.source = AlertInput::Registers;
+    }
+    if args.occurrences_of("gain") > 0 {
+        settings.adc.gain = Some(clap::value_t!(args.value_of("gain"), f32)?);
+    }
+    if args.occurrences_of("offset") > 0 {
+        settings.adc.offset = Some(clap::value_t!(args.value_of("offset"), f32)?);
+    }
+
+    settings.validate()?;
+    Ok(settings)
+}
+
+fn override_value<T>(args: &ArgMatches, name: &str, value: &mut T) -> Result<(), clap::Error>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if args.occurrences_of(name) > 0 {
+        *value = clap::value_t!(args.value_of(name), T)?;
+    }
     Ok(())
 }
+
+//volt config check
+fn config_check(args: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    match load_settings(args) {
+        Ok(settings) => {
+            println!("{}", settings.to_toml()?);
+            let identity = Identity::resolve(&settings.device).unwrap_or_else(|err| {
+                eprintln!("settings error: {}", err);
+                process::exit(2);
+            });
+            println!("device id: {}", identity.device_id);
+            for template in [
+                &settings.mqtt.readings_topic,
+                &settings.mqtt.events_topic,
+                &settings.mqtt.status_topic,
+            ] {
+                println!("topic: {}", identity.topic(template, "{type}"));
+            }
+            println!("settings OK");
+            Ok(())
+        }
+        Err(err) => {
+            eprintln!("settings error: {}", err);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,729 @@
+// Monitoring loop: polls the ADC, follows the alert input and turns the
+// readings into VoltageEvents. It runs as a task owning the ADC, commands go
+// through Control and the events are read from the Events stream.
+
+use std::fs::File;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use i2c_linux::I2c;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{self, Duration};
+
+use crate::adc::{self, ADC};
+use crate::alert::{AlertEvent, AlertSource};
+use crate::async_adc::AsyncAdc;
+use crate::bus::Bus;
+use crate::command::Command;
+use crate::payload::{AlertKind, Reading};
+
+// consecutive timeouts taken as a stuck bus
+const STUCK_TIMEOUTS: u32 = 3;
+
+// lowest/highest register values written by a reset
+pub const RESET_MIN: f32 = 50.0;
+pub const RESET_MAX: f32 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Values {
+    pub current: f32,
+    pub min: f32,
+    pub max: f32,
+    pub alert_under: bool,
+    pub alert_over: bool,
+}
+
+// thresholds and publish interval, changed at runtime by commands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub under_range: f32,
+    pub over_range: f32,
+    pub hysteresis: f32,
+    //min secs between current_volt messages
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum VoltageEvent {
+    //each poll and alert input change
+    Reading(Values),
+    //value: min (under) or max (over) voltage
+    AlertRaised { kind: AlertKind, value: f32 },
+    //value: current voltage
+    AlertCleared { kind: AlertKind, value: f32 },
+    NewLowest(f32),
+    NewHighest(f32),
+}
+
+// VoltageEvent variants, used to filter the events of each sink
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Reading,
+    AlertRaised,
+    AlertCleared,
+    NewLowest,
+    NewHighest,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 5] = [
+        EventKind::Reading,
+        EventKind::AlertRaised,
+        EventKind::AlertCleared,
+        EventKind::NewLowest,
+        EventKind::NewHighest,
+    ];
+}
+
+impl VoltageEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            VoltageEvent::Reading(_) => EventKind::Reading,
+            VoltageEvent::AlertRaised { .. } => EventKind::AlertRaised,
+            VoltageEvent::AlertCleared { .. } => EventKind::AlertCleared,
+            VoltageEvent::NewLowest(_) => EventKind::NewLowest,
+            VoltageEvent::NewHighest(_) => EventKind::NewHighest,
+        }
+    }
+}
+
+// daemon reaction to an ADC error
+enum Recovery {
+    Retry,
+    Reopen,
+    Abort,
+}
+
+fn recovery(err: &adc::Error) -> Recovery {
+    match err {
+        adc::Error::Io(_) | adc::Error::Register { .. } | adc::Error::Timeout(_) | adc::Error::Busy => {
+            Recovery::Retry
+        }
+        adc::Error::NoDevice(_) | adc::Error::Open(..) => Recovery::Reopen,
+        adc::Error::Address(_)
+        | adc::Error::OutOfRange(_)
+        | adc::Error::Verify { .. }
+        | adc::Error::Closed => Recovery::Abort,
+    }
+}
+
+pub fn read_values<B: Bus>(dev: &mut ADC<B>) -> adc::Result<Values> {
+    let (current, alert) = dev.read_value()?;
+    let (alert_over, alert_under) = if alert {
+        dev.read_alert()?
+    } else {
+        (false, false)
+    };
+    let min = dev.read_min_value()?;
+    let max = dev.read_max_value()?;
+    Ok(Values {
+        current,
+        min,
+        max,
+        alert_under,
+        alert_over,
+    })
+}
+
+type Reopen<B> = Arc<dyn Fn(&Limits) -> adc::Result<ADC<B>> + Send + Sync>;
+type Reply = oneshot::Sender<Result<Option<Reading>, String>>;
+
+pub struct Monitor<B: Bus = I2c<File>> {
+    adc: AsyncAdc<B>,
+    alerts: AlertSource,
+    period: Duration,
+    limits: Limits,
+    reopen: Option<Reopen<B>>,
+    //last alert states sent
+    alert_under: bool,
+    alert_over: bool,
+    //NewLowest/NewHighest thresholds
+    min_old: f32,
+    max_old: f32,
+    //consecutive ADC timeouts
+    timeouts: u32,
+}
+
+impl<B: Bus + Send + 'static> Monitor<B> {
+    //"period" between ADC polls
+    pub fn new(adc: AsyncAdc<B>, alerts: AlertSource, limits: Limits, period: Duration) -> Self {
+        Monitor {
+            adc,
+            alerts,
+            period,
+            limits,
+            reopen: None,
+            alert_under: false,
+            alert_over: false,
+            min_old: RESET_MIN,
+            max_old: RESET_MAX,
+            timeouts: 0,
+        }
+    }
+
+    //lowest/highest voltage known before the start
+    pub fn with_extremes(mut self, min: f32, max: f32) -> Self {
+        self.min_old = min;
+        self.max_old = max;
+        self
+    }
+
+    //replace the ADC after a NoDevice/Open error, ex: reopen and set up the bus
+    pub fn with_reopen<F>(mut self, reopen: F) -> Self
+    where
+        F: Fn(&Limits) -> adc::Result<ADC<B>> + Send + Sync + 'static,
+    {
+        self.reopen = Some(Arc::new(reopen));
+        self
+    }
+
+    //run the loop, it stops when Events is dropped or on a fatal ADC error
+    pub fn spawn(self) -> (Control, Events) {
+        let (events_tx, events) = mpsc::channel(32);
+        let (requests_tx, requests) = mpsc::unbounded_channel();
+        let (limits_tx, limits) = watch::channel(self.limits);
+        let (error_tx, error) = oneshot::channel();
+        tokio::spawn(async move {
+            //the error is sent before the events end
+            if let Err(err) = self.run(&events_tx, requests, limits_tx).await {
+                let _ = error_tx.send(err);
+            }
+            drop(events_tx);
+        });
+        (
+            Control {
+                requests: requests_tx,
+                limits,
+            },
+            Events { rx: events, error },
+        )
+    }
+
+    async fn run(
+        mut self,
+        events: &mpsc::Sender<VoltageEvent>,
+        mut requests: mpsc::UnboundedReceiver<(Command, Reply)>,
+        limits: watch::Sender<Limits>,
+    ) -> adc::Result<()> {
+        let mut tick = time::interval(self.period);
+        let mut reopen = false;
+        loop {
+            let result = tokio::select! {
+                event = self.alerts.next() => match event {
+                    Ok(_) if reopen => continue,
+                    Ok(event) => self.alert(event).await,
+                    Err(err) => {
+                        warn!("alert input error: {}", err);
+                        continue;
+                    }
+                },
+                _ = tick.tick() => self.poll(&mut reopen).await,
+                Some((command, reply)) = requests.recv() => {
+                    let result = self.execute(&command).await;
+                    if result.is_ok() {
+                        let _ = limits.send(self.limits);
+                    }
+                    let _ = reply.send(result);
+                    continue;
+                }
+                _ = events.closed() => return Ok(()),
+            };
+            let values = match result {
+                Ok(values) => values,
+                Err(err) => {
+                    warn!("ADC error: {}", err);
+                    match self.recovery(&err) {
+                        Recovery::Retry => continue,
+                        Recovery::Reopen => {
+                            reopen = true;
+                            continue;
+                        }
+                        Recovery::Abort => return Err(err),
+                    }
+                }
+            };
+            self.timeouts = 0;
+            for event in self.changes(values) {
+                if events.send(event).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    //a stuck bus (repeated timeouts) is reopened, or fatal without reopen hook
+    fn recovery(&mut self, err: &adc::Error) -> Recovery {
+        if let adc::Error::Timeout(_) | adc::Error::Busy = err {
+            self.timeouts += 1;
+            if self.timeouts >= STUCK_TIMEOUTS {
+                warn!("ADC bus stuck after {} timeouts", self.timeouts);
+                self.timeouts = 0;
+                return match self.reopen {
+                    Some(_) => Recovery::Reopen,
+                    None => Recovery::Abort,
+                };
+            }
+        }
+        recovery(err)
+    }
+
+    async fn alert(&mut self, event: AlertEvent) -> adc::Result<Values> {
+        let (current, min, max) = self
+            .adc
+            .call(|dev| {
+                let (current, _) = dev.read_value()?;
+                Ok((current, dev.read_min_value()?, dev.read_max_value()?))
+            })
+            .await?;
+        warn!(
+            "ADC alert: {:?} {}, volt: {}, min: {}, max: {}",
+            event.kind, event.active, current, min, max
+        );
+        let (alert_under, alert_over) = match event.kind {
+            AlertKind::Under => (event.active, self.alert_over),
+            AlertKind::Over => (self.alert_under, event.active),
+        };
+        Ok(Values {
+            current,
+            min,
+            max,
+            alert_under,
+            alert_over,
+        })
+    }
+
+    async fn poll(&mut self, reopen: &mut bool) -> adc::Result<Values> {
+        if *reopen {
+            if let Some(open) = &self.reopen {
+                let (open, limits) = (open.clone(), self.limits);
+                self.adc.reopen(move || open(&limits)).await?;
+                info!("ADC reopened");
+            }
+            *reopen = false;
+        }
+        let values = self.adc.call(read_values).await?;
+
+        // restart the lowest/highest tracking once back in range
+        let limits = self.limits;
+        if values.min < limits.under_range && values.current > limits.under_range {
+            self.adc.write_min_value(RESET_MIN).await.unwrap_or_else(|error| {
+                warn!("ADC write_min_value error: {}", error);
+            });
+        }
+        if values.max > limits.over_range && values.current < limits.over_range {
+            self.adc.write_max_value(RESET_MAX).await.unwrap_or_else(|error| {
+                warn!("ADC write_max_value error: {}", error);
+            });
+        }
+        Ok(values)
+    }
+
+    //events of a reading: the reading, then alert edges and new extremes
+    fn changes(&mut self, values: Values) -> Vec<VoltageEvent> {
+        let mut events = vec![VoltageEvent::Reading(values)];
+        for (kind, active, last, value) in [
+            (AlertKind::Under, values.alert_under, &mut self.alert_under, values.min),
+            (AlertKind::Over, values.alert_over, &mut self.alert_over, values.max),
+        ] {
+            if active != *last {
+                *last = active;
+                events.push(if active {
+                    VoltageEvent::AlertRaised { kind, value }
+                } else {
+                    VoltageEvent::AlertCleared {
+                        kind,
+                        value: values.current,
+                    }
+                });
+            }
+        }
+        if values.min > 0.0 && self.min_old > values.min {
+            self.min_old = values.min - self.limits.hysteresis;
+            events.push(VoltageEvent::NewLowest(values.min));
+        }
+        if self.max_old < values.max {
+            self.max_old = values.max + self.limits.hysteresis;
+            events.push(VoltageEvent::NewHighest(values.max));
+        }
+        events
+    }
+
+    //apply a command, the ADC is written first so a failed write keeps the limits
+    async fn execute(&mut self, command: &Command) -> Result<Option<Reading>, String> {
+        let limits = &mut self.limits;
+        match *command {
+            Command::SetThresholds {
+                under_range,
+                over_range,
+                hysteresis,
+            } => {
+                if under_range.is_none() && over_range.is_none() && hysteresis.is_none() {
+                    return Err("under_range, over_range or hysteresis needed".to_string());
+                }
+                let under = under_range.unwrap_or(limits.under_range);
+                let over = over_range.unwrap_or(limits.over_range);
+                if under >= over {
+                    return Err(format!("under_range ({}) must be lower than over_range ({})", under, over));
+                }
+                //every value is checked before the first write
+                self.adc
+                    .call(move |dev| {
+                        for value in [under, over] {
+                            if dev.scale().to_code(value).is_none() {
+                                return Err(adc::Error::OutOfRange(value));
+                            }
+                        }
+                        if let Some(value) = hysteresis {
+                            if dev.scale().to_code_delta(value).is_none() {
+                                return Err(adc::Error::OutOfRange(value));
+                            }
+                        }
+                        dev.set_alert_under_range(under)?;
+                        dev.set_alert_over_range(over)?;
+                        match hysteresis {
+                            Some(value) => dev.set_alert_hysteresis(value),
+                            None => Ok(()),
+                        }
+                    })
+                    .await
+                    .map_err(|err| err.to_string())?;
+                limits.under_range = under;
+                limits.over_range = over;
+                limits.hysteresis = hysteresis.unwrap_or(limits.hysteresis);
+            }
+            Command::SetHysteresis { hysteresis } => {
+                self.adc
+                    .set_alert_hysteresis(hysteresis)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                limits.hysteresis = hysteresis;
+            }
+            Command::SetPublishInterval { interval } => limits.timeout = interval,
+            Command::ResetMinMax => {
+                self.adc
+                    .call(|dev| {
+                        dev.write_min_value(RESET_MIN)?;
+                        dev.write_max_value(RESET_MAX)
+                    })
+                    .await
+                    .map_err(|err| err.to_string())?;
+                self.min_old = RESET_MIN;
+                self.max_old = RESET_MAX;
+            }
+            Command::Read => {
+                let values = self.adc.call(read_values).await.map_err(|err| err.to_string())?;
+                return Ok(Some(Reading {
+                    current: values.current,
+                    min: values.min,
+                    max: values.max,
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Handle to a running Monitor
+#[derive(Clone)]
+pub struct Control {
+    requests: mpsc::UnboundedSender<(Command, Reply)>,
+    limits: watch::Receiver<Limits>,
+}
+
+impl Control {
+    //run a command in the monitor loop
+    pub async fn execute(&self, command: Command) -> Result<Option<Reading>, String> {
+        let (reply, result) = oneshot::channel();
+        self.requests
+            .send((command, reply))
+            .map_err(|_| "monitor stopped".to_string())?;
+        result.await.unwrap_or_else(|_| Err("monitor stopped".to_string()))
+    }
+
+    pub fn limits(&self) -> Limits {
+        *self.limits.borrow()
+    }
+}
+
+// VoltageEvents of a running Monitor, ends when the monitor stops
+pub struct Events {
+    rx: mpsc::Receiver<VoltageEvent>,
+    error: oneshot::Receiver<adc::Error>,
+}
+
+impl Events {
+    pub async fn next(&mut self) -> Option<VoltageEvent> {
+        self.rx.recv().await
+    }
+
+    //fatal ADC error that stopped the monitor, once the events ended
+    pub fn error(&mut self) -> Option<adc::Error> {
+        self.error.try_recv().ok()
+    }
+}
+
+impl Stream for Events {
+    type Item = VoltageEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<VoltageEvent>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::UnboundedSender;
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::bus::RegisterFile;
+
+    const PERIOD: Duration = Duration::from_secs(1);
+
+    // 0.016 V per code with the default scale
+    fn code(volts: f32) -> u16 {
+        (volts / 0.016).round() as u16
+    }
+
+    struct Board {
+        adc: AsyncAdc<RegisterFile>,
+        alerts: UnboundedSender<AlertEvent>,
+    }
+
+    impl Board {
+        //registers as the ADC sends them, MSB first
+        async fn set(&self, reg: u8, value: u16) {
+            self.adc
+                .call(move |dev| {
+                    dev.bus_mut().set_word(reg, value.swap_bytes());
+                    Ok(())
+                })
+                .await
+                .unwrap();
+        }
+
+        async fn set_current(&self, volts: f32) {
+            self.set(0x00, code(volts)).await;
+        }
+
+        //ALERT line and alert registers of an under range alert
+        async fn set_alert_under(&self, active: bool, volts: f32) {
+            self.set(0x00, code(volts) | if active { 0x8000 } else { 0 }).await;
+            self.set(0x01, if active { 0x0100 } else { 0 }).await;
+            self.alerts.send(AlertEvent { kind: AlertKind::Under, active }).unwrap();
+        }
+    }
+
+    // monitor of 12 V with the lowest/highest registers reset, like the daemon start
+    async fn start() -> (Board, Control, Events) {
+        let adc = AsyncAdc::inline(ADC::with_bus(RegisterFile::new()));
+        let (alerts, source) = AlertSource::mock();
+        let board = Board { adc: adc.clone(), alerts };
+        board.set_current(12.0).await;
+        adc.write_min_value(RESET_MIN).await.unwrap();
+        adc.write_max_value(RESET_MAX).await.unwrap();
+        let (min, max) = (adc.read_min_value().await.unwrap(), adc.read_max_value().await.unwrap());
+        let limits = Limits {
+            under_range: 9.0,
+            over_range: 50.0,
+            hysteresis: 0.5,
+            timeout: 60,
+        };
+        let (control, events) = Monitor::new(adc, source, limits, PERIOD)
+            .with_extremes(min, max)
+            .spawn();
+        (board, control, events)
+    }
+
+    async fn reading(events: &mut Events) -> Values {
+        match events.next().await {
+            Some(VoltageEvent::Reading(values)) => values,
+            other => panic!("expected a reading, got {:?}", other),
+        }
+    }
+
+    fn assert_volts(found: f32, expected: f32) {
+        assert!((found - expected).abs() < 0.01, "{} != {}", found, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn readings_each_period() {
+        let (board, _control, mut events) = start().await;
+        let start = Instant::now();
+        assert_volts(reading(&mut events).await.current, 12.0);
+
+        board.set_current(12.5).await;
+        let values = reading(&mut events).await;
+        assert_eq!(start.elapsed(), PERIOD);
+        assert_volts(values.current, 12.5);
+        assert!(!values.alert_under && !values.alert_over);
+
+        reading(&mut events).await;
+        assert_eq!(start.elapsed(), 2 * PERIOD);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn alert_raised_and_cleared() {
+        let (board, _control, mut events) = start().await;
+        reading(&mut events).await;
+
+        board.set(0x06, code(8.5)).await;
+        board.set_alert_under(true, 8.6).await;
+        assert!(reading(&mut events).await.alert_under);
+        let raised = events.next().await.unwrap();
+        assert_eq!(raised.kind(), EventKind::AlertRaised);
+        match raised {
+            VoltageEvent::AlertRaised { kind, value } => {
+                assert_eq!(kind, AlertKind::Under);
+                assert_volts(value, 8.5);
+            }
+            other => panic!("{:?}", other),
+        }
+        match events.next().await {
+            Some(VoltageEvent::NewLowest(min)) => assert_volts(min, 8.5),
+            other => panic!("{:?}", other),
+        }
+
+        // polls keep the alert while the registers report it
+        assert!(reading(&mut events).await.alert_under);
+
+        board.set_alert_under(false, 11.0).await;
+        assert!(!reading(&mut events).await.alert_under);
+        match events.next().await {
+            Some(VoltageEvent::AlertCleared { kind, value }) => {
+                assert_eq!(kind, AlertKind::Under);
+                assert_volts(value, 11.0);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn new_lowest_and_highest_with_hysteresis() {
+        let (board, _control, mut events) = start().await;
+        reading(&mut events).await;
+
+        board.set(0x07, code(13.6)).await;
+        reading(&mut events).await;
+        assert_eq!(events.next().await.map(|event| event.kind()), Some(EventKind::NewHighest));
+
+        // within the hysteresis of the last highest
+        board.set(0x07, code(14.0)).await;
+        reading(&mut events).await;
+        board.set(0x07, code(14.4)).await;
+        reading(&mut events).await;
+        match events.next().await {
+            Some(VoltageEvent::NewHighest(max)) => assert_volts(max, 14.4),
+            other => panic!("{:?}", other),
+        }
+
+        board.set(0x06, code(11.2)).await;
+        reading(&mut events).await;
+        match events.next().await {
+            Some(VoltageEvent::NewLowest(min)) => assert_volts(min, 11.2),
+            other => panic!("{:?}", other),
+        }
+        board.set(0x06, code(10.8)).await;
+        assert_volts(reading(&mut events).await.min, 10.8);
+        board.set(0x06, code(10.6)).await;
+        reading(&mut events).await;
+        assert_eq!(events.next().await.map(|event| event.kind()), Some(EventKind::NewLowest));
+    }
+
+    // bus never answering in time
+    struct Stuck;
+
+    impl Bus for Stuck {
+        fn read_byte_data(&mut self, _reg: u8) -> std::io::Result<u8> {
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(0)
+        }
+
+        fn read_word_data(&mut self, _reg: u8) -> std::io::Result<u16> {
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(0)
+        }
+
+        fn write_byte_data(&mut self, _reg: u8, _value: u8) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn write_word_data(&mut self, _reg: u8, _value: u16) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // multi-thread: the error must be there once the events end
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn stuck_bus_without_reopen_is_fatal() {
+        let adc = AsyncAdc::spawn(ADC::with_bus(Stuck), Duration::from_millis(10));
+        let limits = Limits {
+            under_range: 9.0,
+            over_range: 50.0,
+            hysteresis: 0.5,
+            timeout: 60,
+        };
+        let (_control, mut events) =
+            Monitor::new(adc, AlertSource::Registers, limits, Duration::from_millis(10)).spawn();
+        assert_eq!(events.next().await, None);
+        assert!(matches!(
+            events.error(),
+            Some(adc::Error::Timeout(_)) | Some(adc::Error::Busy)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn commands_run_in_the_loop() {
+        let (board, control, mut events) = start().await;
+        reading(&mut events).await;
+
+        let command = Command::SetThresholds {
+            under_range: Some(10.0),
+            over_range: None,
+            hysteresis: None,
+        };
+        assert_eq!(control.execute(command).await, Ok(None));
+        assert_volts(control.limits().under_range, 10.0);
+        let bad = Command::SetThresholds {
+            under_range: Some(60.0),
+            over_range: None,
+            hysteresis: None,
+        };
+        assert!(control.execute(bad).await.is_err());
+        assert_volts(control.limits().under_range, 10.0);
+
+        //all or nothing
+        let bad = Command::SetThresholds {
+            under_range: Some(11.0),
+            over_range: None,
+            hysteresis: Some(80.0),
+        };
+        assert!(control.execute(bad).await.is_err());
+        let limits = control.limits();
+        assert_volts(limits.under_range, 10.0);
+        assert_volts(limits.hysteresis, 0.5);
+        let command = Command::SetThresholds {
+            under_range: Some(11.0),
+            over_range: None,
+            hysteresis: Some(1.0),
+        };
+        assert_eq!(control.execute(command).await, Ok(None));
+        let limits = control.limits();
+        assert_volts(limits.under_range, 11.0);
+        assert_volts(limits.hysteresis, 1.0);
+
+        board.set_current(12.8).await;
+        let read = control.execute(Command::Read).await.unwrap().unwrap();
+        assert_volts(read.current, 12.8);
+
+        drop(events);
+        time::sleep(2 * PERIOD).await;
+        assert!(control.execute(Command::Read).await.is_err());
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+use paho_mqtt as mqtt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+#[cfg(feature = "ssl")]
+use crate::settings::TlsSettings;
+use crate::encoding::Encoding;
+use crate::payload::{Body, Payload, State, StatusInfo};
+use crate::settings::{MessageSettings, MqttSettings};
+use crate::topic::Identity;
+
+pub fn create_client(settings: &MqttSettings, identity: &Identity) -> mqtt::Result<mqtt::AsyncClient> {
+    mqtt::CreateOptionsBuilder::new()
+        .server_uri(settings.brokers.first().map_or("", String::as_str))
+        .client_id(identity.render(&settings.client_id))
+        .create_client()
+}
+
+pub fn connect_options(settings: &MqttSettings, will: Option<mqtt::Message>) -> mqtt::Result<mqtt::ConnectOptions> {
+    let mut builder = mqtt::ConnectOptionsBuilder::new();
+    builder
+        .server_uris(&settings.brokers)
+        .keep_alive_interval(Duration::from_secs(settings.keep_alive))
+        .clean_session(settings.clean_session)
+        //no 3.1 fallback, paho retries it on its own when the broker drops a new connection
+        .mqtt_version(mqtt::MQTT_VERSION_3_1_1);
+    if let Some(will) = will {
+        builder.will_message(will);
+    }
+    if let Some(username) = &settings.username {
+        builder.user_name(username.as_str());
+    }
+    if let Some(password) = &settings.password {
+        builder.password(password.as_str());
+    }
+    #[cfg(feature = "ssl")]
+    if let Some(tls) = &settings.tls {
+        builder.ssl_options(ssl_options(tls)?);
+    }
+    Ok(builder.finalize())
+}
+
+#[cfg(feature = "ssl")]
+fn ssl_options(tls: &TlsSettings) -> mqtt::Result<mqtt::SslOptions> {
+    let mut builder = mqtt::SslOptionsBuilder::new();
+    if let Some(ca_file) = &tls.ca_file {
+        builder.trust_store(ca_file)?;
+    }
+    if let Some(cert_file) = &tls.cert_file {
+        builder.key_store(cert_file)?;
+    }
+    if let Some(key_file) = &tls.key_file {
+        builder.private_key(key_file)?;
+    }
+    if let Some(password) = &tls.key_password {
+        builder.private_key_password(password.as_str());
+    }
+    builder
+        .enable_server_cert_auth(tls.verify_server)
+        .verify(tls.verify_hostname);
+    Ok(builder.finalize())
+}
+
+// Reconnect delay, doubled after each failed attempt up to reconnect_max
+pub(crate) struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(settings: &MqttSettings) -> Self {
+        let min = Duration::from_secs(settings.reconnect_min);
+        Backoff {
+            min,
+            max: Duration::from_secs(settings.reconnect_max),
+            delay: min,
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.delay = self.min;
+    }
+
+    //delay before the next attempt
+    pub(crate) fn next(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(self.max);
+        delay
+    }
+}
+
+// Client connected again with the backoff after a failed attempt or a lost
+// connection, shared by the publisher and the Sparkplug node
+pub(crate) struct Connection {
+    pub(crate) client: mqtt::AsyncClient,
+    backoff: Backoff,
+    next_attempt: Instant,
+    //woken by the connection lost callback
+    lost: Arc<Notify>,
+}
+
+impl Connection {
+    pub(crate) fn new(mut client: mqtt::AsyncClient, settings: &MqttSettings) -> Self {
+        let lost = Arc::new(Notify::new());
+        let notify = lost.clone();
+        client.set_connection_lost_callback(move |_| {
+            warn!("MQTT connection lost");
+            notify.notify_one();
+        });
+        Connection {
+            client,
+            backoff: Backoff::new(settings),
+            next_attempt: Instant::now(),
+            lost,
+        }
+    }
+
+    //connect with "options" when disconnected and the backoff delay is over,
+    //true on success
+    pub(crate) async fn connect<F>(&mut self, options: F) -> bool
+    where
+        F: FnOnce() -> mqtt::Result<mqtt::ConnectOptions>,
+    {
+        if self.client.is_connected() || Instant::now() < self.next_attempt {
+            return false;
+        }
+        let result = match options() {
+            Ok(conn_opts) => self.client.connect(conn_opts).await.map(|_| ()),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => {
+                self.backoff.reset();
+                true
+            }
+            Err(err) => {
+                let delay = self.backoff.next();
+                warn!("MQTT unable to connect: {}, retry in {:?}", err, delay);
+                self.next_attempt = Instant::now() + delay;
+                false
+            }
+        }
+    }
+
+    //ready when connect() must be called again: the backoff delay is over or
+    //the connection was lost
+    pub(crate) async fn reconnect_due(&mut self) {
+        let lost = self.lost.clone();
+        if self.client.is_connected() {
+            lost.notified().await;
+        } else {
+            //a loss noticed before this wait still delays the next attempt
+            tokio::select! {
+                biased;
+                _ = lost.notified() => {}
+                _ = time::sleep_until(self.next_attempt) => return,
+            }
+        }
+        //paho is still closing the lost connection, retry after the backoff
+        self.next_attempt = Instant::now() + self.backoff.next();
+    }
+}
+
+//message with the qos and retained flag of its type
+pub fn message(options: &MessageSettings, topic: &str, payload: Vec<u8>) -> mqtt::Message {
+    if options.retained {
+        mqtt::Message::new_retained(topic, payload, options.qos)
+    } else {
+        mqtt::Message::new(topic, payload, options.qos)
+    }
+}
+
+//secs since the epoch, payload "timeStamp"
+pub fn timestamp() -> f64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_secs_f64(),
+        Err(_) => {
+            warn!("SystemTime before UNIX EPOCH!");
+            0.0
+        }
+    }
+}
+
+// Retained daemon status: "online" with version and current configuration after each
+// connect, "offline" as Last Will (clean: false) or on shutdown (clean: true)
+#[derive(Debug, Clone)]
+pub struct Status {
+    topic: String,
+    options: MessageSettings,
+    encoding: Encoding,
+    version: String,
+    config: watch::Receiver<serde_json::Value>,
+}
+
+impl Status {
+    pub fn new(settings: &MqttSettings, identity: &Identity, version: &str, config: watch::Receiver<serde_json::Value>) -> Self {
+        Status {
+            topic: identity.topic(&settings.status_topic, "status"),
+            options: settings.status,
+            encoding: settings.status_encoding,
+            version: version.to_string(),
+            config,
+        }
+    }
+
+    pub fn online(&self) -> mqtt::Message {
+        let payload = Payload::new(
+            timestamp(),
+            Body::Status(StatusInfo {
+                state: State::Online,
+                version: Some(self.version.clone()),
+                config: Some(self.config.borrow().clone()),
+                clean: None,
+            }),
+        );
+        message(&self.options, &self.topic, self.encoding.encode(&payload))
+    }
+
+    //the Last Will is sent by the broker later, without timeStamp
+    pub fn offline(&self, clean: bool) -> mqtt::Message {
+        let mut payload = Payload::new(
+            timestamp(),
+            Body::Status(StatusInfo {
+                state: State::Offline,
+                version: None,
+                config: None,
+                clean: Some(clean),
+            }),
+        );
+        if !clean {
+            payload.time_stamp = None;
+        }
+        message(&self.options, &self.topic, self.encoding.encode(&payload))
+    }
+}
+
+//message waiting to be published
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Outgoing {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: i32,
+    pub retained: bool,
+}
+
+impl From<&mqtt::Message> for Outgoing {
+    fn from(msg: &mqtt::Message) -> Self {
+        Outgoing {
+            topic: msg.topic().to_string(),
+            payload: msg.payload().to_vec(),
+            qos: msg.qos(),
+            retained: msg.retained(),
+        }
+    }
+}
+
+impl Outgoing {
+    fn to_message(&self) -> mqtt::Message {
+        mqtt::MessageBuilder::new()
+            .topic(self.topic.as_str())
+            .payload(self.payload.as_slice())
+            .qos(self.qos)
+            .retained(self.retained)
+            .finalize()
+    }
+}
+
+// Bounded FIFO of unsent messages, optionally mirrored in a JSON lines file
+struct Outbox {
+    queue: VecDeque<Outgoing>,
+    capacity: usize,
+    file: Option<PathBuf>,
+    dirty: bool,
+}
+
+impl Outbox {
+    fn new(capacity: usize, file: Option<PathBuf>) -> Self {
+        let mut outbox = Outbox {
+            queue: VecDeque::new(),
+            capacity,
+            file,
+            dirty: false,
+        };
+        if let Some(path) = outbox.file.clone() {
+            match fs::File::open(&path) {
+                Ok(file) => {
+                    for line in BufReader::new(file).lines().map_while(Result::ok) {
+                        match serde_json::from_str(&line) {
+                            Ok(msg) => outbox.push(msg),
+                            Err(err) => warn!("MQTT buffer {}: invalid entry: {}", path.display(), err),
+                        }
+                    }
+                    info!("MQTT buffer: {} messages restored from {}", outbox.queue.len(), path.display());
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => warn!("MQTT buffer {}: {}", path.display(), err),
+            }
+        }
+        outbox
+    }
+
+    fn push(&mut self, msg: Outgoing) {
+        if self.queue.len() >= self.capacity {
+            if let Some(dropped) = self.queue.pop_front() {
+                warn!("MQTT buffer full, message to {} dropped", dropped.topic);
+            }
+        }
+        self.queue.push_back(msg);
+        self.dirty = true;
+    }
+
+    //publish in order, stops at the first failure while disconnected
+    async fn flush(&mut self, client: &mqtt::AsyncClient) {
+        while let Some(msg) = self.queue.front() {
+            match client.publish(msg.to_message()).await {
+                Ok(()) => {}
+                Err(err) if !client.is_connected() => {
+                    warn!("MQTT publish error, {} messages buffered: {}", self.queue.len(), err);
+                    return;
+                }
+                Err(err) => error!("MQTT message to {} discarded: {}", msg.topic, err),
+            }
+            self.queue.pop_front();
+            self.dirty = true;
+        }
+    }
+
+    fn save(&mut self) {
+        let path = match (&self.file, self.dirty) {
+            (Some(path), true) => path,
+            _ => return,
+        };
+        let result = if self.queue.is_empty() {
+            fs::remove_file(path).or_else(|err| match err.kind() {
+                std::io::ErrorKind::NotFound => Ok(()),
+                _ => Err(err),
+            })
+        } else {
+            let mut data = String::new();
+            for msg in &self.queue {
+                if let Ok(line) = serde_json::to_string(msg) {
+                    data.push_str(&line);
+                    data.push('\n');
+                }
+            }
+            fs::write(path, data)
+        };
+        match result {
+            Ok(()) => self.dirty = false,
+            Err(err) => warn!("MQTT buffer {}: {}", path.display(), err),
+        }
+    }
+}
+
+// Topic filter subscribed after each connect, the received messages are sent to "tx"
+pub struct Subscription {
+    pub topic: String,
+    pub qos: i32,
+    pub tx: mpsc::UnboundedSender<mqtt::Message>,
+}
+
+// Handle to the publisher task. Messages are queued and sent in order, the
+// task keeps reconnecting with backoff while the broker is unreachable and
+// publishes the status before the queued messages after each connect.
+#[derive(Clone)]
+pub struct Publisher {
+    tx: mpsc::UnboundedSender<Outgoing>,
+}
+
+impl Publisher {
+    //drop every Publisher handle and await the JoinHandle to flush and disconnect
+    pub fn spawn(
+        mut client: mqtt::AsyncClient,
+        conn_opts: mqtt::ConnectOptions,
+        settings: &MqttSettings,
+        status: Status,
+        subscription: Option<Subscription>,
+    ) -> (Publisher, JoinHandle<()>) {
+        let subscription = subscription.map(|Subscription { topic, qos, tx }| {
+            client.set_message_callback(move |_, msg| {
+                if let Some(msg) = msg {
+                    let _ = tx.send(msg);
+                }
+            });
+            (topic, qos)
+        });
+        let (tx, rx) = mpsc::unbounded_channel();
+        let outbox = Outbox::new(settings.buffer_size, settings.buffer_file.as_ref().map(PathBuf::from));
+        let connection = Connection::new(client, settings);
+        let task = tokio::spawn(run(connection, conn_opts, status, subscription, rx, outbox));
+        (Publisher { tx }, task)
+    }
+
+    pub fn publish(&self, msg: mqtt::Message) {
+        if self.tx.send(Outgoing::from(&msg)).is_err() {
+            error!("MQTT publisher closed, message to {} lost", msg.topic());
+        }
+    }
+}
+
+#[cfg(test)]
+impl Publisher {
+    //no client, the messages go to the receiver
+    pub(crate) fn channel() -> (Publisher, mpsc::UnboundedReceiver<Outgoing>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Publisher { tx }, rx)
+    }
+}
+
+async fn run(
+    mut connection: Connection,
+    conn_opts: mqtt::ConnectOptions,
+    status: Status,
+    subscription: Option<(String, i32)>,
+    mut rx: mpsc::UnboundedReceiver<Outgoing>,
+    mut outbox: Outbox,
+) {
+    loop {
+        if connection.connect(|| Ok(conn_opts.clone())).await {
+            info!("MQTT connected, {} messages buffered", outbox.queue.len());
+            let client = &connection.client;
+            if let Err(err) = client.publish(status.online()).await {
+                warn!("MQTT status publish error: {}", err);
+            }
+            if let Some((topic, qos)) = &subscription {
+                match client.subscribe(topic.as_str(), *qos).await {
+                    Ok(_) => info!("MQTT subscribed to {}", topic),
+                    Err(err) => error!("MQTT subscribe to {} error: {}", topic, err),
+                }
+            }
+        }
+        if connection.client.is_connected() {
+            outbox.flush(&connection.client).await;
+        }
+        outbox.save();
+
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => outbox.push(msg),
+                None => break,
+            },
+            _ = connection.reconnect_due() => {}
+        }
+    }
+
+    let client = &connection.client;
+    if client.is_connected() {
+        outbox.flush(client).await;
+        if let Err(err) = client.publish(status.offline(true)).await {
+            warn!("MQTT status publish error: {}", err);
+        }
+        //a normal disconnect discards the Last Will
+        if let Err(err) = client.disconnect(None).await {
+            warn!("MQTT disconnect error: {}", err);
+        }
+    }
+    if !outbox.queue.is_empty() {
+        warn!("MQTT publisher closed with {} messages unsent", outbox.queue.len());
+    }
+    outbox.save();
+}
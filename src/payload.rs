@@ -0,0 +1,285 @@
+// MQTT message payloads, JSON: {"schema", "timeStamp", "type", "value"}.
+// SCHEMA_VERSION is bumped on incompatible changes, "volt schema" prints the
+// JSON schema.
+
+use schemars::{schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(title = "volt message", description = "Message published by the volt daemon")]
+pub struct Payload {
+    #[schemars(description = "Payload format version")]
+    pub schema: u32,
+    #[serde(rename = "timeStamp", default, skip_serializing_if = "Option::is_none")]
+    #[schemars(description = "Seconds since the Unix epoch, missing in the Last Will")]
+    pub time_stamp: Option<f64>,
+    #[serde(flatten)]
+    pub body: Body,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Body {
+    #[schemars(description = "Current input voltage (volts)")]
+    CurrentVolt(f32),
+    #[schemars(description = "Lowest voltage since the last reset (volts)")]
+    LowestVolt(f32),
+    #[schemars(description = "Highest voltage since the last reset (volts)")]
+    HighestVolt(f32),
+    #[schemars(description = "Under/over range alert raised or cleared")]
+    AlertStatusVolt(Alert),
+    #[schemars(description = "Daemon online/offline status")]
+    Status(StatusInfo),
+    #[schemars(description = "Result of a command received on the command topic")]
+    Response(Response),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Alert {
+    #[schemars(description = "Min (under) or max (over) voltage when raised, current voltage when cleared")]
+    pub value: f32,
+    pub active: bool,
+    pub kind: AlertKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    Under,
+    Over,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StatusInfo {
+    pub state: State,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(description = "Daemon version, online only")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(description = "Active ADC, alert and publish settings, online only")]
+    pub config: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(description = "Offline only: true on shutdown, false in the Last Will")]
+    pub clean: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Online,
+    Offline,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Response {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(description = "\"id\" of the request, any JSON value")]
+    pub id: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(description = "Command name, missing if the request can't be parsed")]
+    pub command: Option<String>,
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(description = "\"read\" command only")]
+    pub reading: Option<Reading>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Reading {
+    pub current: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Response {
+    pub fn new(id: Option<serde_json::Value>, command: Option<&str>, result: Result<Option<Reading>, String>) -> Self {
+        let (reading, error) = match result {
+            Ok(reading) => (reading, None),
+            Err(error) => (None, Some(error)),
+        };
+        Response {
+            id,
+            command: command.map(String::from),
+            ok: error.is_none(),
+            error,
+            reading,
+        }
+    }
+}
+
+impl Body {
+    //"type" field, also used for {type} in topics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Body::CurrentVolt(_) => "current_volt",
+            Body::LowestVolt(_) => "lowest_volt",
+            Body::HighestVolt(_) => "highest_volt",
+            Body::AlertStatusVolt(_) => "alert_status_volt",
+            Body::Status(_) => "status",
+            Body::Response(_) => "response",
+        }
+    }
+}
+
+impl Payload {
+    pub fn new(time_stamp: f64, body: Body) -> Self {
+        Payload {
+            schema: SCHEMA_VERSION,
+            time_stamp: Some(time_stamp),
+            body,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("payload serialization")
+    }
+
+    pub fn from_json(data: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(data)
+    }
+}
+
+//JSON schema of Payload, pretty printed
+pub fn json_schema() -> String {
+    serde_json::to_string_pretty(&schema_for!(Payload)).expect("schema serialization")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mqtt::Status;
+    use crate::settings::MqttSettings;
+    use crate::topic::Identity;
+
+    fn bodies() -> Vec<Body> {
+        vec![
+            Body::CurrentVolt(12.25),
+            Body::LowestVolt(11.5),
+            Body::HighestVolt(13.75),
+            Body::AlertStatusVolt(Alert {
+                value: 9.25,
+                active: true,
+                kind: AlertKind::Under,
+            }),
+            Body::Status(StatusInfo {
+                state: State::Online,
+                version: Some("1.0.0".to_string()),
+                config: Some(json!({"alert": {"under_range": 9.5}})),
+                clean: None,
+            }),
+            Body::Status(StatusInfo {
+                state: State::Offline,
+                version: None,
+                config: None,
+                clean: Some(true),
+            }),
+            Body::Response(Response::new(
+                Some(json!("7")),
+                Some("read"),
+                Ok(Some(Reading {
+                    current: 12.0,
+                    min: 11.0,
+                    max: 13.0,
+                })),
+            )),
+            Body::Response(Response::new(Some(json!(7)), Some("reset_min_max"), Ok(None))),
+            Body::Response(Response::new(None, None, Err("invalid request".to_string()))),
+        ]
+    }
+
+    #[test]
+    fn json_round_trip() {
+        for body in bodies() {
+            let payload = Payload::new(1_600_000_000.5, body);
+            let json: Value = serde_json::from_str(&payload.to_json()).unwrap();
+            assert_eq!(json["schema"], SCHEMA_VERSION);
+            assert_eq!(json["timeStamp"], 1_600_000_000.5);
+            assert_eq!(json["type"], payload.body.name());
+            assert_eq!(Payload::from_json(&payload.to_json()).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn json_format() {
+        let payload = Payload::new(2.5, Body::CurrentVolt(12.25));
+        assert_eq!(
+            payload.to_json(),
+            r#"{"schema":1,"timeStamp":2.5,"type":"current_volt","value":12.25}"#
+        );
+        let alert = Alert {
+            value: 50.5,
+            active: false,
+            kind: AlertKind::Over,
+        };
+        let payload = Payload::new(2.5, Body::AlertStatusVolt(alert));
+        assert_eq!(
+            serde_json::from_str::<Value>(&payload.to_json()).unwrap()["value"],
+            json!({"value": 50.5, "active": false, "kind": "over"})
+        );
+    }
+
+    #[test]
+    fn last_will_without_time_stamp() {
+        let payload = Payload {
+            schema: SCHEMA_VERSION,
+            time_stamp: None,
+            body: Body::Status(StatusInfo {
+                state: State::Offline,
+                version: None,
+                config: None,
+                clean: Some(false),
+            }),
+        };
+        assert_eq!(
+            payload.to_json(),
+            r#"{"schema":1,"type":"status","value":{"state":"offline","clean":false}}"#
+        );
+        assert_eq!(Payload::from_json(&payload.to_json()).unwrap(), payload);
+
+        let identity = Identity {
+            device_id: "dev".to_string(),
+            hostname: "host".to_string(),
+            channel: "0".to_string(),
+        };
+        let (config_tx, config_rx) = tokio::sync::watch::channel(Value::Null);
+        let status = Status::new(&MqttSettings::default(), &identity, "1.0.0", config_rx);
+        let will = status.offline(false);
+        assert_eq!(will.topic(), "STATUS/volt");
+        assert_eq!(Payload::from_json(&will.payload_str()).unwrap(), payload);
+        let shutdown = Payload::from_json(&status.offline(true).payload_str()).unwrap();
+        assert!(shutdown.time_stamp.is_some());
+        //online status with the configuration at the time of the connect
+        config_tx.send(json!({"alert": {"under_range": 11.5}})).unwrap();
+        let online = Payload::from_json(&status.online().payload_str()).unwrap();
+        match online.body {
+            Body::Status(info) => assert_eq!(info.config, Some(json!({"alert": {"under_range": 11.5}}))),
+            body => panic!("unexpected body {:?}", body),
+        }
+    }
+
+    #[test]
+    fn schema_lists_every_body() {
+        let schema: Value = serde_json::from_str(&json_schema()).unwrap();
+        assert_eq!(schema["title"], "volt message");
+        assert_eq!(schema["required"], json!(["schema"]));
+        assert!(schema["properties"]["timeStamp"].is_object());
+        let types: Vec<&Value> = schema["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|variant| &variant["properties"]["type"]["enum"][0])
+            .collect();
+        let mut names: Vec<&str> = bodies().iter().map(Body::name).collect();
+        names.dedup();
+        assert_eq!(types, names);
+        for definition in ["Alert", "StatusInfo", "Response", "Reading"] {
+            assert!(schema["definitions"][definition].is_object(), "{}", definition);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+// Protobuf messages of proto/volt.proto, written by hand (no protoc at build
+// time). Keep the tags in sync with the .proto file.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Payload {
+    #[prost(uint32, tag = "1")]
+    pub schema: u32,
+    #[prost(double, optional, tag = "2")]
+    pub time_stamp: Option<f64>,
+    #[prost(oneof = "payload::Body", tags = "3, 4, 5, 6, 7, 8")]
+    pub body: Option<payload::Body>,
+}
+
+pub mod payload {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Body {
+        #[prost(float, tag = "3")]
+        CurrentVolt(f32),
+        #[prost(float, tag = "4")]
+        LowestVolt(f32),
+        #[prost(float, tag = "5")]
+        HighestVolt(f32),
+        #[prost(message, tag = "6")]
+        AlertStatusVolt(super::Alert),
+        #[prost(message, tag = "7")]
+        Status(super::Status),
+        #[prost(message, tag = "8")]
+        Response(super::Response),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Alert {
+    #[prost(float, tag = "1")]
+    pub value: f32,
+    #[prost(bool, tag = "2")]
+    pub active: bool,
+    #[prost(enumeration = "AlertKind", tag = "3")]
+    pub kind: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum AlertKind {
+    Unspecified = 0,
+    Under = 1,
+    Over = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Status {
+    #[prost(enumeration = "State", tag = "1")]
+    pub state: i32,
+    #[prost(string, optional, tag = "2")]
+    pub version: Option<String>,
+    //JSON object
+    #[prost(string, optional, tag = "3")]
+    pub config: Option<String>,
+    #[prost(bool, optional, tag = "4")]
+    pub clean: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum State {
+    Unspecified = 0,
+    Online = 1,
+    Offline = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Response {
+    #[prost(string, optional, tag = "1")]
+    pub id: Option<String>,
+    #[prost(string, optional, tag = "2")]
+    pub command: Option<String>,
+    #[prost(bool, tag = "3")]
+    pub ok: bool,
+    #[prost(string, optional, tag = "4")]
+    pub error: Option<String>,
+    #[prost(message, optional, tag = "5")]
+    pub reading: Option<Reading>,
+    //JSON value, non-string ids
+    #[prost(string, optional, tag = "6")]
+    pub id_json: Option<String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Reading {
+    #[prost(float, tag = "1")]
+    pub current: f32,
+    #[prost(float, tag = "2")]
+    pub min: f32,
+    #[prost(float, tag = "3")]
+    pub max: f32,
+}
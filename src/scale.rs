@@ -0,0 +1,228 @@
+// Conversion between ADC121C02x 12-bit codes and volts at the board input.
+//
+// nominal volts = code * reference / 4096 * divider (or the calibration table)
+// volts         = gain * nominal + offset
+
+pub const FULL_SCALE: u16 = 0x0FFF;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scale {
+    //ADC reference voltage (VA)
+    pub reference: f32,
+    //input divider ratio (Vin / Vadc)
+    pub divider: f32,
+    pub gain: f32,
+    pub offset: f32,
+    //(code, nominal volts) points, sorted by code
+    table: Vec<(u16, f32)>,
+}
+
+impl Default for Scale {
+    //0.016 V/LSB
+    fn default() -> Self {
+        Scale::new(4.096, 16.0)
+    }
+}
+
+impl Scale {
+    pub fn new(reference: f32, divider: f32) -> Self {
+        Scale {
+            reference,
+            divider,
+            gain: 1.0,
+            offset: 0.0,
+            table: Vec::new(),
+        }
+    }
+
+    pub fn with_correction(mut self, gain: f32, offset: f32) -> Self {
+        self.gain = gain;
+        self.offset = offset;
+        self
+    }
+
+    //multi-point table, replaces reference and divider in the conversion.
+    //Needs at least two points with increasing codes and volts.
+    pub fn with_table(mut self, mut table: Vec<(u16, f32)>) -> Result<Self, String> {
+        table.sort_by_key(|(code, _)| *code);
+        if table.len() < 2 {
+            return Err("calibration table needs at least two points".to_string());
+        }
+        for pair in table.windows(2) {
+            let ((code_a, volts_a), (code_b, volts_b)) = (pair[0], pair[1]);
+            if code_a == code_b || volts_a >= volts_b {
+                return Err(format!(
+                    "calibration table not increasing between codes {} and {}",
+                    code_a, code_b
+                ));
+            }
+        }
+        if let Some((code, _)) = table.iter().find(|(code, _)| *code > FULL_SCALE) {
+            return Err(format!("calibration table code out of range: {}", code));
+        }
+        self.table = table;
+        Ok(self)
+    }
+
+    pub fn table(&self) -> &[(u16, f32)] {
+        &self.table
+    }
+
+    //volts per code, without gain and offset
+    pub fn lsb(&self) -> f32 {
+        match (self.table.first(), self.table.last()) {
+            (Some((code_a, volts_a)), Some((code_b, volts_b))) if self.table.len() > 1 => {
+                (volts_b - volts_a) / (*code_b as f32 - *code_a as f32)
+            }
+            _ => self.reference * self.divider / 4096.0,
+        }
+    }
+
+    pub fn to_volts(&self, code: u16) -> f32 {
+        let code = (code & FULL_SCALE) as f32;
+        let nominal = match self.segment(|(c, _)| *c as f32 >= code) {
+            Some(((code_a, volts_a), (code_b, volts_b))) => {
+                volts_a + (code - code_a) * (volts_b - volts_a) / (code_b - code_a)
+            }
+            None => code * self.lsb(),
+        };
+        self.gain * nominal + self.offset
+    }
+
+    //None if the value is outside of the ADC range
+    pub fn to_code(&self, volts: f32) -> Option<u16> {
+        let nominal = (volts - self.offset) / self.gain;
+        let code = match self.segment(|(_, v)| *v >= nominal) {
+            Some(((code_a, volts_a), (code_b, volts_b))) => {
+                code_a + (nominal - volts_a) * (code_b - code_a) / (volts_b - volts_a)
+            }
+            None => nominal / self.lsb(),
+        };
+        in_range(code.round())
+    }
+
+    //codes for a voltage difference (ex: hysteresis), offset doesn't apply
+    pub fn to_code_delta(&self, volts: f32) -> Option<u16> {
+        in_range((volts / (self.gain * self.lsb())).round())
+    }
+
+    //table segment for the first point matching "upper", end segments extrapolate
+    fn segment<F>(&self, upper: F) -> Option<((f32, f32), (f32, f32))>
+    where
+        F: Fn(&(u16, f32)) -> bool,
+    {
+        if self.table.len() < 2 {
+            return None;
+        }
+        let i = self
+            .table
+            .iter()
+            .position(upper)
+            .unwrap_or(self.table.len() - 1)
+            .max(1);
+        let (code_a, volts_a) = self.table[i - 1];
+        let (code_b, volts_b) = self.table[i];
+        Some(((code_a as f32, volts_a), (code_b as f32, volts_b)))
+    }
+}
+
+fn in_range(code: f32) -> Option<u16> {
+    if (0.0..=FULL_SCALE as f32).contains(&code) {
+        Some(code as u16)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-3, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn default_scale() {
+        let scale = Scale::default();
+        assert_close(scale.lsb(), 0.016);
+        assert_close(scale.to_volts(0), 0.0);
+        assert_close(scale.to_volts(750), 12.0);
+        assert_close(scale.to_volts(FULL_SCALE), 65.52);
+        //alert flag and reserved bits ignored
+        assert_close(scale.to_volts(0x8000 | 750), 12.0);
+        assert_eq!(scale.to_code(12.0), Some(750));
+        assert_eq!(scale.to_code(12.007), Some(750));
+        assert_eq!(scale.to_code(65.52), Some(FULL_SCALE));
+        assert_eq!(scale.to_code(65.6), None);
+        assert_eq!(scale.to_code(-0.1), None);
+    }
+
+    #[test]
+    fn gain_and_offset() {
+        let scale = Scale::default().with_correction(1.02, -0.15);
+        assert_close(scale.to_volts(750), 12.0 * 1.02 - 0.15);
+        for code in (0..=FULL_SCALE).step_by(7) {
+            assert_eq!(scale.to_code(scale.to_volts(code)), Some(code));
+        }
+        let table = Scale::default()
+            .with_table(vec![(0, 0.0), (1000, 10.0), (4095, 70.0)])
+            .unwrap()
+            .with_correction(0.98, 0.2);
+        for code in (0..=FULL_SCALE).step_by(7) {
+            assert_eq!(table.to_code(table.to_volts(code)), Some(code));
+        }
+    }
+
+    #[test]
+    fn code_delta() {
+        let scale = Scale::default();
+        assert_eq!(scale.to_code_delta(0.0), Some(0));
+        assert_eq!(scale.to_code_delta(0.5), Some(31));
+        assert_eq!(scale.to_code_delta(1.2), Some(75));
+        //offset doesn't apply, gain does
+        let corrected = Scale::default().with_correction(2.0, 5.0);
+        assert_eq!(corrected.to_code_delta(1.2), Some(38));
+        assert_eq!(scale.to_code_delta(-1.0), None);
+        assert_eq!(scale.to_code_delta(70.0), None);
+    }
+
+    #[test]
+    fn table_interpolation() {
+        let scale = Scale::default()
+            .with_table(vec![(1000, 10.0), (2000, 30.0), (100, 1.0)])
+            .unwrap();
+        assert_eq!(scale.table(), &[(100, 1.0), (1000, 10.0), (2000, 30.0)]);
+        //between the points
+        assert_close(scale.to_volts(550), 5.5);
+        assert_close(scale.to_volts(1500), 20.0);
+        assert_eq!(scale.to_code(5.5), Some(550));
+        assert_eq!(scale.to_code(20.0), Some(1500));
+        //past the ends, with the end segments
+        assert_close(scale.to_volts(50), 0.5);
+        assert_close(scale.to_volts(3000), 50.0);
+        assert_eq!(scale.to_code(0.5), Some(50));
+        assert_eq!(scale.to_code(50.0), Some(3000));
+        assert_eq!(scale.to_code(90.0), None);
+        //average slope
+        assert_close(scale.lsb(), 29.0 / 1900.0);
+    }
+
+    #[test]
+    fn invalid_tables() {
+        let table = |points: Vec<(u16, f32)>| Scale::default().with_table(points);
+        assert!(table(vec![(100, 1.0)]).is_err());
+        assert!(table(Vec::new()).is_err());
+        //volts not increasing with the codes
+        assert!(table(vec![(100, 5.0), (200, 4.0)]).is_err());
+        assert!(table(vec![(200, 1.0), (100, 2.0)]).is_err());
+        assert!(table(vec![(100, 1.0), (200, 1.0)]).is_err());
+        //duplicate code
+        assert!(table(vec![(100, 1.0), (100, 2.0), (200, 3.0)]).is_err());
+        assert!(table(vec![(100, 1.0), (100, 1.0), (200, 3.0)]).is_err());
+        assert_eq!(
+            table(vec![(100, 1.0), (0x1000, 70.0)]),
+            Err("calibration table code out of range: 4096".to_string())
+        );
+    }
+}
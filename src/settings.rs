@@ -0,0 +1,810 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::adc;
+use crate::calibration;
+use crate::encoding::Encoding;
+use crate::input;
+use crate::monitor::EventKind;
+use crate::payload::AlertKind;
+use crate::scale::Scale;
+use crate::topic;
+
+pub const SETTINGS_PATH: &str = "/etc/volt.toml";
+
+#[derive(Debug)]
+pub enum Error {
+    //settings file can't be read
+    Io(PathBuf, io::Error),
+    //invalid TOML or unknown/mistyped keys
+    Parse(PathBuf, toml::de::Error),
+    //value not allowed, "field" is the dotted key (ex: alert.under_range)
+    Invalid { field: &'static str, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(path, err) => write!(f, "can't read {}: {}", path.display(), err),
+            Error::Parse(path, err) => write!(f, "invalid settings in {}: {}", path.display(), err),
+            Error::Invalid { field, message } => write!(f, "invalid \"{}\": {}", field, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(_, err) => Some(err),
+            Error::Parse(_, err) => Some(err),
+            Error::Invalid { .. } => None,
+        }
+    }
+}
+
+fn positive(value: f32) -> bool {
+    value.is_finite() && value > 0.0
+}
+
+fn invalid<T>(field: &'static str, message: String) -> Result<T, Error> {
+    Err(Error::Invalid { field, message })
+}
+
+// volt daemon settings, every section and key is optional
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub device: DeviceSettings,
+    pub adc: AdcSettings,
+    pub alert: AlertSettings,
+    pub publish: PublishSettings,
+    pub mqtt: MqttSettings,
+    pub homeassistant: HomeAssistantSettings,
+    pub sparkplug: SparkplugSettings,
+    pub http: HttpSettings,
+    pub gpio: GpioSettings,
+    pub evdev: EvdevSettings,
+    //extra destinations of the monitor events (toml can't write an empty
+    //array after the tables)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sinks: Vec<SinkSettings>,
+}
+
+// identity of the unit, used in the topic and client id templates
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceSettings {
+    //{device_id} source: config (id), hostname or file (id_file)
+    pub id_source: IdSource,
+    pub id: String,
+    pub id_file: String,
+    //{channel}, name of the measured input
+    pub channel: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdSource {
+    Config,
+    Hostname,
+    File,
+}
+
+impl Default for DeviceSettings {
+    fn default() -> Self {
+        DeviceSettings {
+            id_source: IdSource::Hostname,
+            id: String::new(),
+            id_file: "/etc/machine-id".to_string(),
+            channel: "0".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdcSettings {
+    pub bus: String,
+    pub address: u16,
+    //timeout of each i2c operation (millis)
+    pub bus_timeout: u64,
+    pub vref: f32,
+    pub divider: f32,
+    pub calibration: String,
+    //override the calibration file
+    pub gain: Option<f32>,
+    pub offset: Option<f32>,
+    //multi-point conversion table, replaces vref and divider
+    //(last field, TOML tables go after plain values)
+    pub table: Vec<TablePoint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TablePoint {
+    pub code: u16,
+    pub volts: f32,
+}
+
+impl Default for AdcSettings {
+    fn default() -> Self {
+        let scale = Scale::default();
+        AdcSettings {
+            bus: adc::BUS_PATH.to_string(),
+            address: adc::SLAVE_ADDR,
+            bus_timeout: 1000,
+            vref: scale.reference,
+            divider: scale.divider,
+            calibration: calibration::CALIBRATION_PATH.to_string(),
+            gain: None,
+            offset: None,
+            table: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertSettings {
+    pub under_range: f32,
+    pub over_range: f32,
+    pub hysteresis: f32,
+    //ALERT line input: evdev, gpio or registers (polled, see publish.poll_tick)
+    pub source: AlertInput,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertInput {
+    Evdev,
+    Gpio,
+    Registers,
+}
+
+impl Default for AlertSettings {
+    fn default() -> Self {
+        AlertSettings {
+            under_range: 9.5,
+            over_range: 50.0,
+            hysteresis: 1.0,
+            source: AlertInput::Evdev,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PublishSettings {
+    //min secs between current_volt messages
+    pub timeout: u64,
+    //ADC poll period (secs)
+    pub tick: f32,
+    //ADC poll period without alert input (secs), alerts are read from the registers
+    pub poll_tick: f32,
+}
+
+impl Default for PublishSettings {
+    fn default() -> Self {
+        PublishSettings {
+            timeout: 60,
+            tick: 3.0,
+            poll_tick: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSettings {
+    //tried in order until one connects
+    pub brokers: Vec<String>,
+    //empty: assigned by the broker (needs clean_session),
+    //may contain {device_id}, {hostname} and {channel}
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    //secs
+    pub keep_alive: u64,
+    pub clean_session: bool,
+    //reconnect backoff (secs), doubled after each failed attempt
+    pub reconnect_min: u64,
+    pub reconnect_max: u64,
+    //max messages kept while the broker is unreachable, the oldest are dropped
+    pub buffer_size: usize,
+    //keep unsent messages in this file across restarts
+    pub buffer_file: Option<String>,
+    //topic templates, may contain {device_id}, {hostname}, {channel} and {type}
+    //current/lowest/highest volt messages
+    pub readings_topic: String,
+    //alert messages
+    pub events_topic: String,
+    //retained online/offline messages, "offline" is also the Last Will
+    pub status_topic: String,
+    //command requests (topic filter, wildcards allowed), empty: no commands
+    pub command_topic: String,
+    pub command_qos: i32,
+    //command responses
+    pub response_topic: String,
+    //monitor events published: reading (current_volt), alert_raised,
+    //alert_cleared, new_lowest and new_highest
+    pub events: Vec<EventKind>,
+    //payload encoding of each topic: json, cbor or protobuf
+    pub readings_encoding: Encoding,
+    pub events_encoding: Encoding,
+    pub status_encoding: Encoding,
+    pub response_encoding: Encoding,
+    pub current_volt: MessageSettings,
+    pub lowest_volt: MessageSettings,
+    pub highest_volt: MessageSettings,
+    pub alert_status_volt: MessageSettings,
+    pub status: MessageSettings,
+    pub response: MessageSettings,
+    //TLS options for ssl:// and mqtts:// brokers (needs the "ssl" feature)
+    pub tls: Option<TlsSettings>,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        MqttSettings {
+            brokers: vec!["tcp://localhost:1883".to_string()],
+            client_id: String::new(),
+            username: None,
+            password: None,
+            keep_alive: 60,
+            clean_session: true,
+            reconnect_min: 1,
+            reconnect_max: 60,
+            buffer_size: 1000,
+            buffer_file: None,
+            readings_topic: "VOLT".to_string(),
+            events_topic: "EVENTS/volt".to_string(),
+            status_topic: "STATUS/volt".to_string(),
+            command_topic: String::new(),
+            command_qos: 1,
+            response_topic: "RESPONSE/volt".to_string(),
+            events: EventKind::ALL.to_vec(),
+            readings_encoding: Encoding::Json,
+            events_encoding: Encoding::Json,
+            status_encoding: Encoding::Json,
+            response_encoding: Encoding::Json,
+            current_volt: MessageSettings::default(),
+            lowest_volt: MessageSettings::default(),
+            highest_volt: MessageSettings::default(),
+            alert_status_volt: MessageSettings::default(),
+            status: MessageSettings {
+                qos: 1,
+                retained: true,
+            },
+            response: MessageSettings {
+                qos: 1,
+                retained: false,
+            },
+            tls: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    //PEM CA certificates of the broker, None: system trust store
+    pub ca_file: Option<String>,
+    //PEM client certificate for mutual TLS, may also hold the private key
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    pub key_password: Option<String>,
+    //check the broker certificate chain
+    pub verify_server: bool,
+    //check the broker host name against its certificate
+    pub verify_hostname: bool,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        TlsSettings {
+            ca_file: None,
+            cert_file: None,
+            key_file: None,
+            key_password: None,
+            verify_server: true,
+            verify_hostname: true,
+        }
+    }
+}
+
+//publish options for one message type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageSettings {
+    pub qos: i32,
+    pub retained: bool,
+}
+
+// Home Assistant MQTT discovery, needs the JSON encoding on the readings,
+// events and status topics
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HomeAssistantSettings {
+    pub enabled: bool,
+    pub discovery_prefix: String,
+    //device name in Home Assistant, may contain {device_id}, {hostname} and {channel}
+    pub name: String,
+    //discovery configs are always retained
+    pub qos: i32,
+}
+
+impl Default for HomeAssistantSettings {
+    fn default() -> Self {
+        HomeAssistantSettings {
+            enabled: false,
+            discovery_prefix: "homeassistant".to_string(),
+            name: "volt {device_id}".to_string(),
+            qos: 1,
+        }
+    }
+}
+
+// Sparkplug B mode, replaces the readings, events and status topics
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SparkplugSettings {
+    pub enabled: bool,
+    pub group_id: String,
+    //may contain {device_id}, {hostname} and {channel}
+    pub edge_node_id: String,
+    pub device_id: String,
+}
+
+impl Default for SparkplugSettings {
+    fn default() -> Self {
+        SparkplugSettings {
+            enabled: false,
+            group_id: "volt".to_string(),
+            edge_node_id: "{device_id}".to_string(),
+            device_id: "adc{channel}".to_string(),
+        }
+    }
+}
+
+// local HTTP API (needs the "http" feature)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpSettings {
+    pub enabled: bool,
+    //address:port, the API has no authentication
+    pub listen: String,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            enabled: false,
+            listen: "127.0.0.1:8080".to_string(),
+        }
+    }
+}
+
+// ALERT line on a GPIO character device (alert.source = "gpio")
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GpioSettings {
+    pub chip: String,
+    //line offset in the chip
+    pub line: u32,
+    //alert active while the line is low
+    pub active_low: bool,
+    //millis the line must be stable after an edge
+    pub debounce: u64,
+    //alert raised by the line: under or over
+    pub alert: AlertKind,
+}
+
+impl Default for GpioSettings {
+    fn default() -> Self {
+        GpioSettings {
+            chip: "/dev/gpiochip0".to_string(),
+            line: 0,
+            active_low: false,
+            debounce: 10,
+            alert: AlertKind::Under,
+        }
+    }
+}
+
+// alert input device (alert.source = "evdev"), the path is used when name
+// and phys are not set
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EvdevSettings {
+    pub path: String,
+    //lookup by device name (ex: "gpio-keys") and/or physical path
+    pub name: Option<String>,
+    pub phys: Option<String>,
+    //keys reporting the ADC ALERT line
+    //(last field, TOML tables go after plain values)
+    pub keys: Vec<KeySettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeySettings {
+    //key name (ex: KEY_PROG2) or code
+    pub code: String,
+    //alert raised by the key: under or over
+    pub alert: AlertKind,
+    //alert active while the key value is 0
+    #[serde(default)]
+    pub active_low: bool,
+}
+
+impl Default for EvdevSettings {
+    fn default() -> Self {
+        let key = |code: &str| KeySettings {
+            code: code.to_string(),
+            alert: AlertKind::Under,
+            active_low: false,
+        };
+        EvdevSettings {
+            path: "/dev/input/event0".to_string(),
+            name: None,
+            phys: None,
+            keys: vec![key("KEY_PROG1"), key("KEY_PROG2")],
+        }
+    }
+}
+
+// JSON lines {"timeStamp", "type", "value"} of the monitor events
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SinkSettings {
+    //stdout, file or udp
+    #[serde(rename = "type")]
+    pub kind: SinkKind,
+    //file: appended JSON lines
+    pub path: Option<String>,
+    //udp: address:port receiving a datagram per event
+    pub address: Option<String>,
+    //events sent, all by default
+    #[serde(default = "all_events")]
+    pub events: Vec<EventKind>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    Stdout,
+    File,
+    Udp,
+}
+
+fn all_events() -> Vec<EventKind> {
+    EventKind::ALL.to_vec()
+}
+
+impl Settings {
+    //load and validate a settings file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Settings, Error> {
+        let path = path.as_ref();
+        let data = fs::read_to_string(path).map_err(|err| Error::Io(path.to_path_buf(), err))?;
+        let settings: Settings =
+            toml::from_str(&data).map_err(|err| Error::Parse(path.to_path_buf(), err))?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(self)
+    }
+
+    //nominal scale (without calibration)
+    pub fn scale(&self) -> Result<Scale, Error> {
+        let scale = Scale::new(self.adc.vref, self.adc.divider);
+        if self.adc.table.is_empty() {
+            return Ok(scale);
+        }
+        let table = self.adc.table.iter().map(|p| (p.code, p.volts)).collect();
+        scale
+            .with_table(table)
+            .or_else(|message| invalid("adc.table", message))
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let device = &self.device;
+        match device.id_source {
+            IdSource::Config => {
+                if let Err(message) = topic::check_value(&device.id) {
+                    return invalid("device.id", message);
+                }
+            }
+            IdSource::File if device.id_file.is_empty() => {
+                return invalid("device.id_file", "empty path".to_string());
+            }
+            _ => {}
+        }
+        if let Err(message) = topic::check_value(&device.channel) {
+            return invalid("device.channel", message);
+        }
+
+        let adc = &self.adc;
+        if adc.bus.is_empty() {
+            return invalid("adc.bus", "empty path".to_string());
+        }
+        if !adc::SLAVE_ADDRS.contains(&adc.address) {
+            return invalid("adc.address", format!("{:#X} out of ADC121C02x range", adc.address));
+        }
+        if adc.bus_timeout == 0 {
+            return invalid("adc.bus_timeout", "must be greater than 0".to_string());
+        }
+        if !positive(adc.vref) {
+            return invalid("adc.vref", format!("{} must be greater than 0", adc.vref));
+        }
+        if !positive(adc.divider) {
+            return invalid("adc.divider", format!("{} must be greater than 0", adc.divider));
+        }
+        if let Some(gain) = adc.gain {
+            if !positive(gain) {
+                return invalid("adc.gain", format!("{} must be greater than 0", gain));
+            }
+        }
+        let scale = self.scale()?;
+
+        let alert = &self.alert;
+        if scale.to_code(alert.under_range).is_none() {
+            return invalid("alert.under_range", format!("{} out of ADC range", alert.under_range));
+        }
+        if scale.to_code(alert.over_range).is_none() {
+            return invalid("alert.over_range", format!("{} out of ADC range", alert.over_range));
+        }
+        if alert.under_range >= alert.over_range {
+            return invalid(
+                "alert.under_range",
+                format!(
+                    "{} must be lower than alert.over_range ({})",
+                    alert.under_range, alert.over_range
+                ),
+            );
+        }
+        if scale.to_code_delta(alert.hysteresis).is_none() {
+            return invalid("alert.hysteresis", format!("{} out of ADC range", alert.hysteresis));
+        }
+
+        if !positive(self.publish.tick) {
+            return invalid("publish.tick", format!("{} must be greater than 0", self.publish.tick));
+        }
+        if !positive(self.publish.poll_tick) {
+            return invalid("publish.poll_tick", format!("{} must be greater than 0", self.publish.poll_tick));
+        }
+
+        let mqtt = &self.mqtt;
+        if mqtt.brokers.is_empty() {
+            return invalid("mqtt.brokers", "at least one broker URI is needed".to_string());
+        }
+        for uri in &mqtt.brokers {
+            let known = ["tcp://", "mqtt://", "ssl://", "mqtts://", "ws://", "wss://"]
+                .iter()
+                .any(|scheme| uri.starts_with(scheme));
+            if !known {
+                return invalid("mqtt.brokers", format!("{:?} is not a valid broker URI", uri));
+            }
+        }
+        let secure = mqtt
+            .brokers
+            .iter()
+            .any(|uri| ["ssl://", "mqtts://", "wss://"].iter().any(|scheme| uri.starts_with(scheme)));
+        if (secure || mqtt.tls.is_some()) && !cfg!(feature = "ssl") {
+            return invalid("mqtt.tls", "TLS support not built, enable the \"ssl\" feature".to_string());
+        }
+        if let Some(tls) = &mqtt.tls {
+            for (field, file) in [
+                ("mqtt.tls.ca_file", &tls.ca_file),
+                ("mqtt.tls.cert_file", &tls.cert_file),
+                ("mqtt.tls.key_file", &tls.key_file),
+            ] {
+                if let Some(file) = file {
+                    if !Path::new(file).is_file() {
+                        return invalid(field, format!("{:?} not found", file));
+                    }
+                }
+            }
+            if tls.cert_file.is_none() && (tls.key_file.is_some() || tls.key_password.is_some()) {
+                return invalid("mqtt.tls.cert_file", "private key without client certificate".to_string());
+            }
+            if tls.verify_hostname && !tls.verify_server {
+                return invalid(
+                    "mqtt.tls.verify_hostname",
+                    "host name check needs verify_server".to_string(),
+                );
+            }
+        }
+        if let Err(message) = topic::check(&mqtt.client_id, &topic::PLACEHOLDERS[..3]) {
+            return invalid("mqtt.client_id", message);
+        }
+        if mqtt.client_id.is_empty() && !mqtt.clean_session {
+            return invalid("mqtt.client_id", "empty client id needs clean_session".to_string());
+        }
+        if mqtt.reconnect_min == 0 || mqtt.reconnect_max < mqtt.reconnect_min {
+            return invalid(
+                "mqtt.reconnect_max",
+                format!(
+                    "reconnect_min ({}) must be greater than 0 and not greater than reconnect_max ({})",
+                    mqtt.reconnect_min, mqtt.reconnect_max
+                ),
+            );
+        }
+        if mqtt.buffer_size == 0 {
+            return invalid("mqtt.buffer_size", "must be greater than 0".to_string());
+        }
+        if mqtt.password.is_some() && mqtt.username.is_none() {
+            return invalid("mqtt.password", "password without username".to_string());
+        }
+        for (field, message) in [
+            ("mqtt.current_volt.qos", &mqtt.current_volt),
+            ("mqtt.lowest_volt.qos", &mqtt.lowest_volt),
+            ("mqtt.highest_volt.qos", &mqtt.highest_volt),
+            ("mqtt.alert_status_volt.qos", &mqtt.alert_status_volt),
+            ("mqtt.status.qos", &mqtt.status),
+            ("mqtt.response.qos", &mqtt.response),
+        ] {
+            if !(0..=2).contains(&message.qos) {
+                return invalid(field, format!("{} must be 0, 1 or 2", message.qos));
+            }
+        }
+        if !(0..=2).contains(&mqtt.command_qos) {
+            return invalid("mqtt.command_qos", format!("{} must be 0, 1 or 2", mqtt.command_qos));
+        }
+        if let Err(message) = topic::check(&mqtt.command_topic, &topic::PLACEHOLDERS[..3]) {
+            return invalid("mqtt.command_topic", message);
+        }
+        for (field, template) in [
+            ("mqtt.readings_topic", &mqtt.readings_topic),
+            ("mqtt.events_topic", &mqtt.events_topic),
+            ("mqtt.status_topic", &mqtt.status_topic),
+            ("mqtt.response_topic", &mqtt.response_topic),
+        ] {
+            if template.is_empty() || template.contains(['+', '#']) {
+                return invalid(field, format!("{:?} is not a valid publish topic", template));
+            }
+            if let Err(message) = topic::check(template, &topic::PLACEHOLDERS) {
+                return invalid(field, message);
+            }
+        }
+
+        let homeassistant = &self.homeassistant;
+        if homeassistant.enabled {
+            for (field, encoding) in [
+                ("mqtt.readings_encoding", mqtt.readings_encoding),
+                ("mqtt.events_encoding", mqtt.events_encoding),
+                ("mqtt.status_encoding", mqtt.status_encoding),
+            ] {
+                if encoding != Encoding::Json {
+                    return invalid(field, "Home Assistant discovery needs the json encoding".to_string());
+                }
+            }
+            let prefix = &homeassistant.discovery_prefix;
+            if prefix.is_empty() || prefix.contains(['+', '#']) {
+                return invalid(
+                    "homeassistant.discovery_prefix",
+                    format!("{:?} is not a valid publish topic", prefix),
+                );
+            }
+            if let Err(message) = topic::check(&homeassistant.name, &topic::PLACEHOLDERS[..3]) {
+                return invalid("homeassistant.name", message);
+            }
+            if !(0..=2).contains(&homeassistant.qos) {
+                return invalid("homeassistant.qos", format!("{} must be 0, 1 or 2", homeassistant.qos));
+            }
+        }
+
+        let sparkplug = &self.sparkplug;
+        if sparkplug.enabled {
+            if homeassistant.enabled {
+                return invalid("sparkplug.enabled", "not available with homeassistant.enabled".to_string());
+            }
+            if !mqtt.command_topic.is_empty() {
+                return invalid("sparkplug.enabled", "not available with mqtt.command_topic".to_string());
+            }
+            if !mqtt.clean_session {
+                return invalid("mqtt.clean_session", "Sparkplug needs clean_session".to_string());
+            }
+            for (field, id) in [
+                ("sparkplug.group_id", &sparkplug.group_id),
+                ("sparkplug.edge_node_id", &sparkplug.edge_node_id),
+                ("sparkplug.device_id", &sparkplug.device_id),
+            ] {
+                if let Err(message) = topic::check_value(id) {
+                    return invalid(field, message);
+                }
+                if let Err(message) = topic::check(id, &topic::PLACEHOLDERS[..3]) {
+                    return invalid(field, message);
+                }
+            }
+        }
+
+        let http = &self.http;
+        if http.enabled {
+            if !cfg!(feature = "http") {
+                return invalid("http.enabled", "HTTP API not built, enable the \"http\" feature".to_string());
+            }
+            if let Err(err) = http.listen.parse::<SocketAddr>() {
+                return invalid("http.listen", format!("{:?}: {}", http.listen, err));
+            }
+        }
+
+        if self.gpio.chip.is_empty() {
+            return invalid("gpio.chip", "empty path".to_string());
+        }
+        let evdev = &self.evdev;
+        if evdev.path.is_empty() && evdev.name.is_none() && evdev.phys.is_none() {
+            return invalid("evdev.path", "empty path".to_string());
+        }
+        if evdev.keys.is_empty() {
+            return invalid("evdev.keys", "at least one key is needed".to_string());
+        }
+        for key in &evdev.keys {
+            if let Err(message) = input::parse_key(&key.code) {
+                return invalid("evdev.keys", message);
+            }
+        }
+
+        for sink in &self.sinks {
+            match (sink.kind, &sink.path, &sink.address) {
+                (SinkKind::File, None, _) => return invalid("sinks.path", "file sink without path".to_string()),
+                (SinkKind::File, Some(path), _) if path.is_empty() => {
+                    return invalid("sinks.path", "empty path".to_string());
+                }
+                (SinkKind::Udp, _, None) => return invalid("sinks.address", "udp sink without address".to_string()),
+                (SinkKind::Udp, _, Some(address)) => {
+                    if let Err(err) = address.parse::<SocketAddr>() {
+                        return invalid("sinks.address", format!("{:?}: {}", address, err));
+                    }
+                }
+                _ => {}
+            }
+            if sink.events.is_empty() {
+                return invalid("sinks.events", "at least one event type is needed".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_settings_round_trip() {
+        let settings = Settings::default();
+        let data = settings.to_toml().unwrap();
+        assert_eq!(toml::from_str::<Settings>(&data).unwrap(), settings);
+        settings.validate().unwrap();
+    }
+
+    #[test]
+    fn sinks_round_trip() {
+        let settings = Settings {
+            sinks: vec![
+                SinkSettings {
+                    kind: SinkKind::Stdout,
+                    path: None,
+                    address: None,
+                    events: all_events(),
+                },
+                SinkSettings {
+                    kind: SinkKind::Udp,
+                    path: None,
+                    address: Some("127.0.0.1:9000".to_string()),
+                    events: vec![EventKind::AlertRaised, EventKind::AlertCleared],
+                },
+            ],
+            ..Settings::default()
+        };
+        let data = settings.to_toml().unwrap();
+        assert_eq!(toml::from_str::<Settings>(&data).unwrap(), settings);
+    }
+}
@@ -0,0 +1,520 @@
+// Destinations of the monitor events. Each Sink runs in its own thread fed by
+// the FanOut through a bounded queue: a slow or failing sink loses its own
+// events but doesn't hold the others or the daemon loop. Besides MQTT, events
+// can go as JSON lines {"timeStamp", "type", "value"} to stdout, a file or UDP.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error, info, warn};
+use serde::Serialize;
+
+use crate::monitor::{EventKind, Limits, VoltageEvent};
+use crate::mqtt::{message, Publisher};
+use crate::payload::{Alert, Body, Payload};
+use crate::settings::{MqttSettings, SinkKind, SinkSettings};
+use crate::topic::Identity;
+
+// events waiting in each sink queue, the newest are dropped when full
+const QUEUE_SIZE: usize = 256;
+
+// monitor event with its time and the limits in force
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Record {
+    //secs since the epoch
+    pub time_stamp: f64,
+    pub event: VoltageEvent,
+    pub limits: Limits,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    #[serde(rename = "timeStamp")]
+    time_stamp: f64,
+    #[serde(flatten)]
+    event: &'a VoltageEvent,
+}
+
+impl Record {
+    //JSON line without the trailing newline
+    pub fn to_json(&self) -> String {
+        let line = Line {
+            time_stamp: self.time_stamp,
+            event: &self.event,
+        };
+        serde_json::to_string(&line).expect("event serialization")
+    }
+}
+
+//sink of a [[sinks]] entry
+pub fn open(settings: &SinkSettings) -> io::Result<Box<dyn Sink>> {
+    let missing = |field| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} sink without {}", settings.kind, field));
+    Ok(match settings.kind {
+        SinkKind::Stdout => Box::new(Stdout),
+        SinkKind::File => Box::new(FileSink::new(settings.path.as_ref().ok_or_else(|| missing("path"))?)),
+        SinkKind::Udp => {
+            let address = settings.address.as_ref().ok_or_else(|| missing("address"))?;
+            let address = address
+                .parse()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}: {}", address, err)))?;
+            Box::new(UdpSink::new(address)?)
+        }
+    })
+}
+
+pub trait Sink: Send {
+    //sink name in the logs
+    fn name(&self) -> String;
+
+    fn send(&mut self, record: &Record) -> io::Result<()>;
+
+    //called once the last record is sent
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Output {
+    name: String,
+    events: Vec<EventKind>,
+    tx: SyncSender<Record>,
+    thread: thread::JoinHandle<()>,
+}
+
+// Sends each record to the sinks accepting its event kind
+#[derive(Default)]
+pub struct FanOut {
+    outputs: Vec<Output>,
+}
+
+impl FanOut {
+    pub fn new() -> Self {
+        FanOut::default()
+    }
+
+    //start a thread sending the "events" kinds to "sink"
+    pub fn add(&mut self, sink: Box<dyn Sink>, events: &[EventKind]) -> io::Result<()> {
+        let name = sink.name();
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let thread = thread::Builder::new()
+            .name(format!("sink {}", name))
+            .spawn({
+                let name = name.clone();
+                move || run(sink, &name, rx)
+            })?;
+        info!("sink {}: {:?}", name, events);
+        self.outputs.push(Output {
+            name,
+            events: events.to_vec(),
+            tx,
+            thread,
+        });
+        Ok(())
+    }
+
+    pub fn send(&mut self, record: &Record) {
+        let kind = record.event.kind();
+        self.outputs.retain(|output| {
+            if !output.events.contains(&kind) {
+                return true;
+            }
+            match output.tx.try_send(*record) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("sink {} queue full, {:?} event dropped", output.name, kind);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    error!("sink {} stopped, removed", output.name);
+                    false
+                }
+            }
+        });
+    }
+
+    //send the queued records and flush every sink
+    pub fn close(self) {
+        for output in self.outputs {
+            drop(output.tx);
+            if output.thread.join().is_err() {
+                error!("sink {} panicked", output.name);
+            }
+        }
+    }
+}
+
+// the first error is logged, then the recovery with the count of lost events
+fn run(mut sink: Box<dyn Sink>, name: &str, rx: Receiver<Record>) {
+    let mut failed = 0;
+    for record in rx {
+        match sink.send(&record) {
+            Ok(()) if failed > 0 => {
+                info!("sink {} back, {} events lost", name, failed);
+                failed = 0;
+            }
+            Ok(()) => {}
+            Err(err) => {
+                if failed == 0 {
+                    warn!("sink {} error: {}", name, err);
+                }
+                failed += 1;
+            }
+        }
+    }
+    if let Err(err) = sink.flush() {
+        warn!("sink {} flush error: {}", name, err);
+    }
+}
+
+// JSON lines on the standard output
+pub struct Stdout;
+
+impl Sink for Stdout {
+    fn name(&self) -> String {
+        "stdout".to_string()
+    }
+
+    fn send(&mut self, record: &Record) -> io::Result<()> {
+        writeln!(io::stdout().lock(), "{}", record.to_json())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+// JSON lines appended to a file, reopened after a write error (ex: rotation)
+pub struct FileSink {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl FileSink {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileSink {
+            path: path.into(),
+            file: None,
+        }
+    }
+}
+
+impl Sink for FileSink {
+    fn name(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    fn send(&mut self, record: &Record) -> io::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            file => file.insert(OpenOptions::new().create(true).append(true).open(&self.path)?),
+        };
+        let result = writeln!(file, "{}", record.to_json());
+        if result.is_err() {
+            self.file = None;
+        }
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+}
+
+// one JSON datagram per event
+pub struct UdpSink {
+    address: SocketAddr,
+    socket: UdpSocket,
+}
+
+impl UdpSink {
+    pub fn new(address: SocketAddr) -> io::Result<Self> {
+        let local: SocketAddr = if address.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(address)?;
+        socket.set_write_timeout(Some(Duration::from_secs(1)))?;
+        Ok(UdpSink { address, socket })
+    }
+}
+
+impl Sink for UdpSink {
+    fn name(&self) -> String {
+        format!("udp {}", self.address)
+    }
+
+    fn send(&mut self, record: &Record) -> io::Result<()> {
+        self.socket.send(record.to_json().as_bytes()).map(|_| ())
+    }
+}
+
+// Daemon topics: current_volt at most once per publish interval, alerts on the
+// events topic, lowest/highest on the readings topic
+pub struct MqttSink {
+    publisher: Publisher,
+    identity: Identity,
+    settings: MqttSettings,
+    old_time: SystemTime,
+}
+
+impl MqttSink {
+    pub fn new(publisher: Publisher, identity: Identity, settings: &MqttSettings) -> Self {
+        MqttSink {
+            publisher,
+            identity,
+            settings: settings.clone(),
+            old_time: SystemTime::now(),
+        }
+    }
+
+    fn publish(&self, time_stamp: f64, body: Body) {
+        let settings = &self.settings;
+        let (options, topic, encoding) = match body {
+            Body::AlertStatusVolt(_) => (
+                &settings.alert_status_volt,
+                &settings.events_topic,
+                settings.events_encoding,
+            ),
+            Body::LowestVolt(_) => (&settings.lowest_volt, &settings.readings_topic, settings.readings_encoding),
+            Body::HighestVolt(_) => (&settings.highest_volt, &settings.readings_topic, settings.readings_encoding),
+            _ => (&settings.current_volt, &settings.readings_topic, settings.readings_encoding),
+        };
+        let payload = Payload::new(time_stamp, body);
+        let msg = message(
+            options,
+            &self.identity.topic(topic, payload.body.name()),
+            encoding.encode(&payload),
+        );
+        self.publisher.publish(msg);
+    }
+}
+
+impl Sink for MqttSink {
+    fn name(&self) -> String {
+        "mqtt".to_string()
+    }
+
+    fn send(&mut self, record: &Record) -> io::Result<()> {
+        let time_stamp = record.time_stamp;
+        match record.event {
+            VoltageEvent::Reading(values) => {
+                let interval = Duration::from_secs(record.limits.timeout);
+                if self.old_time.elapsed().is_ok_and(|elapse| elapse > interval) && values.current > 0.0 {
+                    self.old_time = SystemTime::now();
+                    debug!("current_volt: {}", values.current);
+                    self.publish(time_stamp, Body::CurrentVolt(values.current));
+                }
+            }
+            VoltageEvent::AlertRaised { kind, value } => {
+                warn!("alert_volt {:?} raised: {}", kind, value);
+                let alert = Alert { value, active: true, kind };
+                self.publish(time_stamp, Body::AlertStatusVolt(alert));
+            }
+            VoltageEvent::AlertCleared { kind, value } => {
+                warn!("alert_volt {:?} cleared: {}", kind, value);
+                let alert = Alert { value, active: false, kind };
+                self.publish(time_stamp, Body::AlertStatusVolt(alert));
+            }
+            VoltageEvent::NewLowest(min) => {
+                warn!("lowest_volt -> {}", min);
+                self.publish(time_stamp, Body::LowestVolt(min));
+            }
+            VoltageEvent::NewHighest(max) => {
+                warn!("highest_volt -> {}", max);
+                self.publish(time_stamp, Body::HighestVolt(max));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::mpsc::Sender;
+
+    use super::*;
+    use crate::monitor::Values;
+    use crate::payload::AlertKind;
+
+    // records sent to a channel
+    struct Collect(Sender<Record>);
+
+    impl Sink for Collect {
+        fn name(&self) -> String {
+            "collect".to_string()
+        }
+
+        fn send(&mut self, record: &Record) -> io::Result<()> {
+            self.0.send(*record).map_err(io::Error::other)
+        }
+    }
+
+    struct Failing;
+
+    impl Sink for Failing {
+        fn name(&self) -> String {
+            "failing".to_string()
+        }
+
+        fn send(&mut self, _record: &Record) -> io::Result<()> {
+            Err(io::Error::other("down"))
+        }
+    }
+
+    // waits until the gate sender is dropped
+    struct Blocked(Receiver<()>);
+
+    impl Sink for Blocked {
+        fn name(&self) -> String {
+            "blocked".to_string()
+        }
+
+        fn send(&mut self, _record: &Record) -> io::Result<()> {
+            let _ = self.0.recv();
+            Ok(())
+        }
+    }
+
+    struct Panicking;
+
+    impl Sink for Panicking {
+        fn name(&self) -> String {
+            "panicking".to_string()
+        }
+
+        fn send(&mut self, _record: &Record) -> io::Result<()> {
+            panic!("sink bug");
+        }
+    }
+
+    fn record(event: VoltageEvent) -> Record {
+        Record {
+            time_stamp: 1_600_000_000.5,
+            event,
+            limits: Limits {
+                under_range: 9.0,
+                over_range: 50.0,
+                hysteresis: 0.5,
+                timeout: 60,
+            },
+        }
+    }
+
+    fn reading(current: f32) -> Record {
+        record(VoltageEvent::Reading(Values {
+            current,
+            min: 11.0,
+            max: 13.0,
+            alert_under: false,
+            alert_over: false,
+        }))
+    }
+
+    fn alert() -> Record {
+        record(VoltageEvent::AlertRaised {
+            kind: AlertKind::Under,
+            value: 8.5,
+        })
+    }
+
+    fn collect(fanout: &mut FanOut, events: &[EventKind]) -> Receiver<Record> {
+        let (tx, rx) = mpsc::channel();
+        fanout.add(Box::new(Collect(tx)), events).unwrap();
+        rx
+    }
+
+    #[test]
+    fn json_lines() {
+        assert_eq!(
+            reading(12.5).to_json(),
+            r#"{"timeStamp":1600000000.5,"type":"reading","value":{"current":12.5,"min":11.0,"max":13.0,"alert_under":false,"alert_over":false}}"#
+        );
+        assert_eq!(
+            alert().to_json(),
+            r#"{"timeStamp":1600000000.5,"type":"alert_raised","value":{"kind":"under","value":8.5}}"#
+        );
+        assert_eq!(
+            record(VoltageEvent::NewHighest(13.5)).to_json(),
+            r#"{"timeStamp":1600000000.5,"type":"new_highest","value":13.5}"#
+        );
+    }
+
+    #[test]
+    fn events_filtered_per_sink() {
+        let mut fanout = FanOut::new();
+        let all = collect(&mut fanout, &EventKind::ALL);
+        let alerts = collect(&mut fanout, &[EventKind::AlertRaised, EventKind::AlertCleared]);
+        let readings = collect(&mut fanout, &[EventKind::Reading]);
+        for record in [reading(12.0), alert(), reading(12.5), record(VoltageEvent::NewLowest(8.5))] {
+            fanout.send(&record);
+        }
+        fanout.close();
+        assert_eq!(all.iter().count(), 4);
+        assert_eq!(alerts.iter().collect::<Vec<_>>(), vec![alert()]);
+        assert_eq!(readings.iter().collect::<Vec<_>>(), vec![reading(12.0), reading(12.5)]);
+    }
+
+    #[test]
+    fn failing_and_full_sinks_dont_hold_the_others() {
+        let mut fanout = FanOut::new();
+        let (gate, blocked) = mpsc::channel();
+        fanout.add(Box::new(Blocked(blocked)), &EventKind::ALL).unwrap();
+        fanout.add(Box::new(Failing), &EventKind::ALL).unwrap();
+        let rx = collect(&mut fanout, &EventKind::ALL);
+        //the blocked sink queue fills up, the others get every record
+        for n in 0..QUEUE_SIZE * 2 {
+            fanout.send(&reading(n as f32));
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), reading(n as f32));
+        }
+        assert_eq!(fanout.outputs.len(), 3);
+        drop(gate);
+        fanout.close();
+    }
+
+    #[test]
+    fn stopped_sink_is_removed() {
+        let mut fanout = FanOut::new();
+        fanout.add(Box::new(Panicking), &EventKind::ALL).unwrap();
+        let rx = collect(&mut fanout, &[EventKind::Reading]);
+        let mut sent = 0;
+        while fanout.outputs.len() == 2 {
+            assert!(sent < 1000, "panicked sink not removed");
+            fanout.send(&reading(12.0));
+            sent += 1;
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(fanout.outputs[0].name, "collect");
+        fanout.send(&reading(13.0));
+        fanout.close();
+        assert_eq!(rx.iter().count(), sent + 1);
+    }
+
+    #[test]
+    fn file_reopened_after_write_error() {
+        //writes to /dev/full fail with ENOSPC
+        let mut sink = FileSink::new("/dev/full");
+        assert!(sink.send(&reading(12.0)).is_err());
+        assert!(sink.file.is_none());
+
+        let path = std::env::temp_dir().join(format!("volt-sink-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        sink.path = path.clone();
+        sink.send(&reading(12.0)).unwrap();
+        sink.send(&alert()).unwrap();
+        sink.flush().unwrap();
+        let data = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(data, format!("{}\n{}\n", reading(12.0).to_json(), alert().to_json()));
+    }
+}
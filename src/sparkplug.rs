@@ -0,0 +1,538 @@
+// Sparkplug B publishing mode: NBIRTH/DBIRTH after each connect, DDATA with
+// the changed metrics, NDEATH as Last Will and on shutdown. Nothing is
+// buffered while disconnected, the births carry the current values. An NCMD
+// "Node Control/Rebirth" publishes the births again.
+
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+use paho_mqtt as mqtt;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use crate::monitor::VoltageEvent;
+use crate::mqtt::{connect_options, Connection};
+use crate::settings::{MqttSettings, SparkplugSettings};
+use crate::sink::{Record, Sink};
+use crate::topic::Identity;
+
+const NAMESPACE: &str = "spBv1.0";
+const REBIRTH: &str = "Node Control/Rebirth";
+
+// Sparkplug B payload subset (org.eclipse.tahu.protobuf.Payload, proto2)
+mod pb {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Payload {
+        #[prost(uint64, optional, tag = "1")]
+        pub timestamp: Option<u64>,
+        #[prost(message, repeated, tag = "2")]
+        pub metrics: Vec<Metric>,
+        #[prost(uint64, optional, tag = "3")]
+        pub seq: Option<u64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Metric {
+        #[prost(string, optional, tag = "1")]
+        pub name: Option<String>,
+        #[prost(uint64, optional, tag = "2")]
+        pub alias: Option<u64>,
+        #[prost(uint64, optional, tag = "3")]
+        pub timestamp: Option<u64>,
+        #[prost(uint32, optional, tag = "4")]
+        pub datatype: Option<u32>,
+        #[prost(oneof = "metric::Value", tags = "11, 12, 14, 15")]
+        pub value: Option<metric::Value>,
+    }
+
+    pub mod metric {
+        #[derive(Clone, PartialEq, prost::Oneof)]
+        pub enum Value {
+            #[prost(uint64, tag = "11")]
+            Long(u64),
+            #[prost(float, tag = "12")]
+            Float(f32),
+            #[prost(bool, tag = "14")]
+            Boolean(bool),
+            #[prost(string, tag = "15")]
+            String(String),
+        }
+    }
+
+    //Sparkplug DataType
+    pub const UINT64: u32 = 8;
+    pub const FLOAT: u32 = 9;
+    pub const BOOLEAN: u32 = 11;
+    pub const STRING: u32 = 12;
+}
+
+// device metrics, sent whole in DBIRTH and by field in DDATA
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metrics {
+    pub current: f32,
+    pub min: f32,
+    pub max: f32,
+    pub alert_under: bool,
+    pub alert_over: bool,
+    pub under_range: f32,
+    pub over_range: f32,
+    pub hysteresis: f32,
+}
+
+impl Metrics {
+    fn list(&self, timestamp: u64) -> Vec<pb::Metric> {
+        vec![
+            float("Voltage/Current", self.current, timestamp),
+            float("Voltage/Lowest", self.min, timestamp),
+            float("Voltage/Highest", self.max, timestamp),
+            boolean("Alert/Under", self.alert_under, timestamp),
+            boolean("Alert/Over", self.alert_over, timestamp),
+            float("Threshold/Under", self.under_range, timestamp),
+            float("Threshold/Over", self.over_range, timestamp),
+            float("Threshold/Hysteresis", self.hysteresis, timestamp),
+        ]
+    }
+}
+
+// Feeds the node task with the readings, DDATA by exception: the current
+// voltage changes at most once per publish interval. Dropping it stops the node.
+pub struct MetricsSink {
+    tx: watch::Sender<Option<Metrics>>,
+    old_time: SystemTime,
+}
+
+impl MetricsSink {
+    pub fn new(tx: watch::Sender<Option<Metrics>>) -> Self {
+        MetricsSink {
+            tx,
+            old_time: SystemTime::now(),
+        }
+    }
+}
+
+impl Sink for MetricsSink {
+    fn name(&self) -> String {
+        "sparkplug".to_string()
+    }
+
+    fn send(&mut self, record: &Record) -> io::Result<()> {
+        let (values, limits) = match record.event {
+            VoltageEvent::Reading(values) => (values, record.limits),
+            _ => return Ok(()),
+        };
+        let mut metrics = Metrics {
+            current: values.current,
+            min: values.min,
+            max: values.max,
+            alert_under: values.alert_under,
+            alert_over: values.alert_over,
+            under_range: limits.under_range,
+            over_range: limits.over_range,
+            hysteresis: limits.hysteresis,
+        };
+        let last = *self.tx.borrow();
+        match (last, self.old_time.elapsed()) {
+            (Some(last), Ok(elapse)) if elapse <= Duration::from_secs(limits.timeout) => {
+                metrics.current = last.current;
+            }
+            _ => self.old_time = SystemTime::now(),
+        }
+        self.tx
+            .send(Some(metrics))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Sparkplug node stopped"))
+    }
+}
+
+fn metric(name: &str, datatype: u32, value: pb::metric::Value, timestamp: u64) -> pb::Metric {
+    pb::Metric {
+        name: Some(name.to_string()),
+        alias: None,
+        timestamp: Some(timestamp),
+        datatype: Some(datatype),
+        value: Some(value),
+    }
+}
+
+fn float(name: &str, value: f32, timestamp: u64) -> pb::Metric {
+    metric(name, pb::FLOAT, pb::metric::Value::Float(value), timestamp)
+}
+
+fn boolean(name: &str, value: bool, timestamp: u64) -> pb::Metric {
+    metric(name, pb::BOOLEAN, pb::metric::Value::Boolean(value), timestamp)
+}
+
+//millis since the epoch
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |n| n.as_millis() as u64)
+}
+
+// Edge node with a single device, keeps the bdSeq/seq numbering
+pub struct Node {
+    group_id: String,
+    edge_node_id: String,
+    device_id: String,
+    version: String,
+    //session bdSeq, set by will()
+    bd_seq: u64,
+    next_bd_seq: u64,
+    seq: u64,
+    //values in the last DBIRTH/DDATA
+    last: Option<Metrics>,
+}
+
+impl Node {
+    pub fn new(settings: &SparkplugSettings, identity: &Identity, version: &str) -> Node {
+        Node {
+            group_id: settings.group_id.clone(),
+            edge_node_id: identity.render(&settings.edge_node_id),
+            device_id: identity.render(&settings.device_id),
+            version: version.to_string(),
+            bd_seq: 0,
+            next_bd_seq: 0,
+            seq: 0,
+            last: None,
+        }
+    }
+
+    pub fn node_topic(&self, kind: &str) -> String {
+        format!("{}/{}/{}/{}", NAMESPACE, self.group_id, kind, self.edge_node_id)
+    }
+
+    pub fn device_topic(&self, kind: &str) -> String {
+        format!("{}/{}/{}/{}/{}", NAMESPACE, self.group_id, kind, self.edge_node_id, self.device_id)
+    }
+
+    //NDEATH of a new session, the Last Will of the next connect
+    pub fn will(&mut self) -> mqtt::Message {
+        self.bd_seq = self.next_bd_seq;
+        self.next_bd_seq = (self.next_bd_seq + 1) % 256;
+        self.death()
+    }
+
+    //NDEATH of the current session
+    pub fn death(&self) -> mqtt::Message {
+        let payload = pb::Payload {
+            timestamp: Some(timestamp()),
+            metrics: vec![self.bd_seq_metric()],
+            seq: None,
+        };
+        mqtt::Message::new(self.node_topic("NDEATH"), prost::Message::encode_to_vec(&payload), 1)
+    }
+
+    //NBIRTH and DBIRTH, restart the seq numbering
+    pub fn births(&mut self, metrics: &Metrics) -> Vec<mqtt::Message> {
+        let now = timestamp();
+        self.seq = 0;
+        let node = pb::Payload {
+            timestamp: Some(now),
+            metrics: vec![
+                self.bd_seq_metric(),
+                boolean(REBIRTH, false, now),
+                metric(
+                    "Properties/Version",
+                    pb::STRING,
+                    pb::metric::Value::String(self.version.clone()),
+                    now,
+                ),
+            ],
+            seq: Some(self.seq),
+        };
+        let device = pb::Payload {
+            timestamp: Some(now),
+            metrics: metrics.list(now),
+            seq: Some(self.next_seq()),
+        };
+        self.last = Some(*metrics);
+        vec![
+            mqtt::Message::new(self.node_topic("NBIRTH"), prost::Message::encode_to_vec(&node), 0),
+            mqtt::Message::new(self.device_topic("DBIRTH"), prost::Message::encode_to_vec(&device), 0),
+        ]
+    }
+
+    //DDATA with the metrics changed since the last message, None without changes
+    pub fn data(&mut self, metrics: &Metrics) -> Option<mqtt::Message> {
+        let now = timestamp();
+        let changed: Vec<_> = match &self.last {
+            Some(last) => metrics
+                .list(now)
+                .into_iter()
+                .zip(last.list(now))
+                .filter(|(new, old)| new != old)
+                .map(|(new, _)| new)
+                .collect(),
+            None => metrics.list(now),
+        };
+        if changed.is_empty() {
+            return None;
+        }
+        self.last = Some(*metrics);
+        let payload = pb::Payload {
+            timestamp: Some(now),
+            metrics: changed,
+            seq: Some(self.next_seq()),
+        };
+        Some(mqtt::Message::new(
+            self.device_topic("DDATA"),
+            prost::Message::encode_to_vec(&payload),
+            0,
+        ))
+    }
+
+    //NCMD with "Node Control/Rebirth" true
+    pub fn is_rebirth(&self, msg: &mqtt::Message) -> bool {
+        if msg.topic() != self.node_topic("NCMD") {
+            return false;
+        }
+        match <pb::Payload as prost::Message>::decode(msg.payload()) {
+            Ok(payload) => payload.metrics.iter().any(|metric| {
+                metric.name.as_deref() == Some(REBIRTH)
+                    && metric.value == Some(pb::metric::Value::Boolean(true))
+            }),
+            Err(err) => {
+                warn!("Sparkplug invalid NCMD: {}", err);
+                false
+            }
+        }
+    }
+
+    fn bd_seq_metric(&self) -> pb::Metric {
+        metric("bdSeq", pb::UINT64, pb::metric::Value::Long(self.bd_seq), timestamp())
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq = (self.seq + 1) % 256;
+        self.seq
+    }
+}
+
+// Run the node on "client", the births wait for the first metrics. Drop the
+// metrics sender and await the JoinHandle to publish NDEATH and disconnect.
+pub fn spawn(
+    mut client: mqtt::AsyncClient,
+    settings: &MqttSettings,
+    node: Node,
+    metrics: watch::Receiver<Option<Metrics>>,
+) -> mqtt::Result<JoinHandle<()>> {
+    // TLS files are checked once here, the options are rebuilt with each will
+    connect_options(settings, None)?;
+    let (tx, commands) = mpsc::unbounded_channel();
+    client.set_message_callback(move |_, msg| {
+        if let Some(msg) = msg {
+            let _ = tx.send(msg);
+        }
+    });
+    let connection = Connection::new(client, settings);
+    Ok(tokio::spawn(run(connection, settings.clone(), node, metrics, commands)))
+}
+
+async fn run(
+    mut connection: Connection,
+    settings: MqttSettings,
+    mut node: Node,
+    mut metrics: watch::Receiver<Option<Metrics>>,
+    mut commands: mpsc::UnboundedReceiver<mqtt::Message>,
+) {
+    let mut born = false;
+    loop {
+        if !connection.client.is_connected() {
+            born = false;
+        }
+        if connection.connect(|| connect_options(&settings, Some(node.will()))).await {
+            info!("Sparkplug node {} connected, bdSeq: {}", node.edge_node_id, node.bd_seq);
+            let topic = node.node_topic("NCMD");
+            if let Err(err) = connection.client.subscribe(topic.as_str(), 1).await {
+                error!("MQTT subscribe to {} error: {}", topic, err);
+            }
+        }
+        let values = *metrics.borrow();
+        if let (true, false, Some(values)) = (connection.client.is_connected(), born, values) {
+            born = true;
+            for msg in node.births(&values) {
+                if let Err(err) = connection.client.publish(msg).await {
+                    warn!("Sparkplug birth publish error: {}", err);
+                    born = false;
+                }
+            }
+        }
+
+        tokio::select! {
+            changed = metrics.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let values = *metrics.borrow();
+                if let (true, Some(values)) = (born, values) {
+                    if let Some(msg) = node.data(&values) {
+                        if let Err(err) = connection.client.publish(msg).await {
+                            warn!("Sparkplug DDATA publish error: {}", err);
+                        }
+                    }
+                }
+            },
+            Some(msg) = commands.recv() => {
+                if node.is_rebirth(&msg) {
+                    info!("Sparkplug rebirth requested");
+                    born = false;
+                }
+            },
+            _ = connection.reconnect_due() => {}
+        }
+    }
+
+    let client = &connection.client;
+    if client.is_connected() {
+        if let Err(err) = client.publish(node.death()).await {
+            warn!("Sparkplug NDEATH publish error: {}", err);
+        }
+        if let Err(err) = client.disconnect(None).await {
+            warn!("MQTT disconnect error: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node() -> Node {
+        let identity = Identity {
+            device_id: "dev".to_string(),
+            hostname: "host".to_string(),
+            channel: "0".to_string(),
+        };
+        Node::new(&SparkplugSettings::default(), &identity, "1.0.0")
+    }
+
+    fn metrics() -> Metrics {
+        Metrics {
+            current: 12.0,
+            min: 11.0,
+            max: 13.0,
+            alert_under: false,
+            alert_over: false,
+            under_range: 9.0,
+            over_range: 50.0,
+            hysteresis: 0.5,
+        }
+    }
+
+    fn decode(msg: &mqtt::Message) -> pb::Payload {
+        prost::Message::decode(msg.payload()).unwrap()
+    }
+
+    fn value(msg: &mqtt::Message, name: &str) -> Option<pb::metric::Value> {
+        let payload = decode(msg);
+        let metric = payload.metrics.into_iter().find(|metric| metric.name.as_deref() == Some(name))?;
+        metric.value
+    }
+
+    fn names(msg: &mqtt::Message) -> Vec<String> {
+        decode(msg).metrics.into_iter().filter_map(|metric| metric.name).collect()
+    }
+
+    fn ncmd(topic: &str, metrics: Vec<pb::Metric>) -> mqtt::Message {
+        let payload = pb::Payload {
+            timestamp: Some(0),
+            metrics,
+            seq: None,
+        };
+        mqtt::Message::new(topic, prost::Message::encode_to_vec(&payload), 1)
+    }
+
+    #[test]
+    fn bd_seq_per_session() {
+        let mut node = node();
+        for n in 0..300 {
+            let bd_seq = Some(pb::metric::Value::Long(n % 256));
+            let will = node.will();
+            assert_eq!(will.topic(), "spBv1.0/volt/NDEATH/dev");
+            assert_eq!(value(&will, "bdSeq"), bd_seq);
+            assert_eq!(decode(&will).seq, None);
+            //the births and the shutdown NDEATH carry the session bdSeq
+            let births = node.births(&metrics());
+            assert_eq!(value(&births[0], "bdSeq"), bd_seq);
+            assert_eq!(value(&node.death(), "bdSeq"), bd_seq);
+        }
+    }
+
+    #[test]
+    fn births() {
+        let mut node = node();
+        node.will();
+        let births = node.births(&metrics());
+        assert_eq!(births[0].topic(), "spBv1.0/volt/NBIRTH/dev");
+        assert_eq!(decode(&births[0]).seq, Some(0));
+        assert_eq!(value(&births[0], REBIRTH), Some(pb::metric::Value::Boolean(false)));
+        assert_eq!(
+            value(&births[0], "Properties/Version"),
+            Some(pb::metric::Value::String("1.0.0".to_string()))
+        );
+        assert_eq!(births[1].topic(), "spBv1.0/volt/DBIRTH/dev/adc0");
+        assert_eq!(decode(&births[1]).seq, Some(1));
+        assert_eq!(names(&births[1]).len(), 8);
+        assert_eq!(value(&births[1], "Voltage/Current"), Some(pb::metric::Value::Float(12.0)));
+        assert_eq!(value(&births[1], "Threshold/Hysteresis"), Some(pb::metric::Value::Float(0.5)));
+
+        //a rebirth restarts the numbering
+        let mut changed = metrics();
+        changed.current = 12.5;
+        assert_eq!(decode(&node.data(&changed).unwrap()).seq, Some(2));
+        let births = node.births(&changed);
+        assert_eq!(decode(&births[0]).seq, Some(0));
+        assert_eq!(decode(&births[1]).seq, Some(1));
+    }
+
+    #[test]
+    fn seq_wraps() {
+        let mut node = node();
+        node.will();
+        node.births(&metrics());
+        let mut values = metrics();
+        for n in 2..600 {
+            values.current += 0.25;
+            let data = node.data(&values).unwrap();
+            assert_eq!(decode(&data).seq, Some(n % 256));
+        }
+    }
+
+    #[test]
+    fn data_by_exception() {
+        let mut node = node();
+        node.will();
+        node.births(&metrics());
+        assert!(node.data(&metrics()).is_none());
+
+        let mut values = metrics();
+        values.current = 8.5;
+        values.alert_under = true;
+        let data = node.data(&values).unwrap();
+        assert_eq!(data.topic(), "spBv1.0/volt/DDATA/dev/adc0");
+        assert_eq!(names(&data), ["Voltage/Current", "Alert/Under"]);
+        assert_eq!(value(&data, "Alert/Under"), Some(pb::metric::Value::Boolean(true)));
+        assert!(node.data(&values).is_none());
+
+        values.under_range = 10.0;
+        assert_eq!(names(&node.data(&values).unwrap()), ["Threshold/Under"]);
+    }
+
+    #[test]
+    fn rebirth_command() {
+        let node = node();
+        let topic = "spBv1.0/volt/NCMD/dev";
+        assert!(node.is_rebirth(&ncmd(topic, vec![boolean(REBIRTH, true, 0)])));
+        let metrics = vec![float("Threshold/Under", 9.0, 0), boolean(REBIRTH, true, 0)];
+        assert!(node.is_rebirth(&ncmd(topic, metrics)));
+
+        assert!(!node.is_rebirth(&ncmd(topic, vec![boolean(REBIRTH, false, 0)])));
+        assert!(!node.is_rebirth(&ncmd(topic, vec![boolean("Node Control/Reboot", true, 0)])));
+        assert!(!node.is_rebirth(&ncmd(topic, vec![float(REBIRTH, 1.0, 0)])));
+        assert!(!node.is_rebirth(&ncmd(topic, Vec::new())));
+        assert!(!node.is_rebirth(&mqtt::Message::new(topic, vec![0xff, 0xff], 1)));
+        for other in ["spBv1.0/volt/NCMD/other", "spBv1.0/volt/DCMD/dev/adc0", "spBv1.0/other/NCMD/dev"] {
+            assert!(!node.is_rebirth(&ncmd(other, vec![boolean(REBIRTH, true, 0)])), "{}", other);
+        }
+    }
+}
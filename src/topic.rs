@@ -0,0 +1,178 @@
+// Topic and client id templates. Placeholders: {device_id}, {hostname},
+// {channel} and, in topics only, {type} (message type, ex: current_volt).
+
+use std::fs;
+use std::io;
+
+use crate::settings::{DeviceSettings, IdSource};
+
+pub const PLACEHOLDERS: [&str; 4] = ["device_id", "hostname", "channel", "type"];
+
+const HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
+
+// Identity of this unit, resolved once at startup
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub device_id: String,
+    pub hostname: String,
+    pub channel: String,
+}
+
+impl Identity {
+    pub fn resolve(settings: &DeviceSettings) -> io::Result<Identity> {
+        let hostname = hostname()?;
+        let device_id = match settings.id_source {
+            IdSource::Config => settings.id.clone(),
+            IdSource::Hostname => hostname.clone(),
+            IdSource::File => read_id(&settings.id_file)?,
+        };
+        for (name, value) in [("device id", &device_id), ("hostname", &hostname)] {
+            check_value(value)
+                .map_err(|message| io::Error::new(io::ErrorKind::InvalidData, format!("{} {}", name, message)))?;
+        }
+        Ok(Identity {
+            device_id,
+            hostname,
+            channel: settings.channel.clone(),
+        })
+    }
+
+    //expand a topic template for a message type
+    pub fn topic(&self, template: &str, kind: &str) -> String {
+        self.expand(template, Some(kind))
+    }
+
+    //expand a template without {type} (client id, subscription filter)
+    pub fn render(&self, template: &str) -> String {
+        self.expand(template, None)
+    }
+
+    fn expand(&self, template: &str, kind: Option<&str>) -> String {
+        // templates are checked by Settings::validate, unknown names stay as is
+        expand(template, |name| match name {
+            "device_id" => Some(self.device_id.as_str()),
+            "hostname" => Some(self.hostname.as_str()),
+            "channel" => Some(self.channel.as_str()),
+            "type" => kind,
+            _ => None,
+        })
+        .unwrap_or_else(|_| template.to_string())
+    }
+}
+
+//check the placeholders of a template, "allowed" is the list of names
+pub fn check(template: &str, allowed: &[&str]) -> Result<(), String> {
+    expand(template, |name| if allowed.contains(&name) { Some("") } else { None }).map(|_| ())
+}
+
+//value usable in a topic level: not empty, no wildcard or level separator
+pub fn check_value(value: &str) -> Result<(), String> {
+    if value.is_empty() || value.contains(['+', '#', '/']) {
+        return Err(format!("{:?} can't be used in a topic", value));
+    }
+    Ok(())
+}
+
+fn expand<'a, F>(template: &str, value: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<&'a str>,
+{
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        result.push_str(&rest[..start]);
+        if rest[start..].starts_with('}') {
+            return Err(format!("unmatched \"}}\" in {:?}", template));
+        }
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err(format!("unmatched \"{{\" in {:?}", template)),
+        };
+        let name = &rest[start + 1..end];
+        match value(name) {
+            Some(value) => result.push_str(value),
+            None => return Err(format!("unknown placeholder {{{}}} in {:?}", name, template)),
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn hostname() -> io::Result<String> {
+    fs::read_to_string(HOSTNAME_PATH)
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .map(|name| name.trim().to_string())
+}
+
+fn read_id(path: &str) -> io::Result<String> {
+    let id = fs::read_to_string(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("device id file {}: {}", path, err)))?;
+    Ok(id.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    fn identity() -> Identity {
+        Identity {
+            device_id: "dev1".to_string(),
+            hostname: "host".to_string(),
+            channel: "2".to_string(),
+        }
+    }
+
+    #[test]
+    fn placeholders() {
+        let identity = identity();
+        assert_eq!(
+            identity.topic("volt/{hostname}/{device_id}/{channel}/{type}", "current_volt"),
+            "volt/host/dev1/2/current_volt"
+        );
+        assert_eq!(identity.topic("{type}-{type}", "alert"), "alert-alert");
+        assert_eq!(identity.topic("VOLT/plain", "alert"), "VOLT/plain");
+        assert_eq!(identity.render("volt-{device_id}"), "volt-dev1");
+        assert_eq!(identity.topic("", "alert"), "");
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert_eq!(
+            check("volt/{device_id", &PLACEHOLDERS),
+            Err("unmatched \"{\" in \"volt/{device_id\"".to_string())
+        );
+        assert_eq!(
+            check("volt/device_id}", &PLACEHOLDERS),
+            Err("unmatched \"}\" in \"volt/device_id}\"".to_string())
+        );
+        assert!(check("volt/{device_id}}", &PLACEHOLDERS).is_err());
+        assert!(check("volt/{{device_id}", &PLACEHOLDERS).is_err());
+        assert_eq!(
+            check("volt/{serial}", &PLACEHOLDERS),
+            Err("unknown placeholder {serial} in \"volt/{serial}\"".to_string())
+        );
+        assert!(check("volt/{}", &PLACEHOLDERS).is_err());
+        check("volt/{hostname}/{device_id}/{channel}/{type}", &PLACEHOLDERS).unwrap();
+        //unchecked templates are used as is
+        assert_eq!(identity().render("volt/{serial}"), "volt/{serial}");
+    }
+
+    #[test]
+    fn no_type_in_client_id() {
+        assert!(check("volt-{type}", &PLACEHOLDERS[..3]).is_err());
+        check("volt-{device_id}-{hostname}-{channel}", &PLACEHOLDERS[..3]).unwrap();
+        let mut settings = Settings::default();
+        settings.mqtt.client_id = "volt-{type}".to_string();
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn topic_values() {
+        check_value("dev-1_a.b").unwrap();
+        for value in ["", "a+b", "#", "a/b", "/"] {
+            assert!(check_value(value).is_err(), "{:?} accepted", value);
+        }
+    }
+}